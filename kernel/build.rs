@@ -0,0 +1,34 @@
+//! Assembles the user programs in `src/programs` with the host's binutils,
+//! so the binaries embedded into the kernel always match their sources.
+
+use std::{env, path::PathBuf, process::Command};
+
+/// Where user programs are linked, in the lower half that is theirs.
+const TEXT_SEGMENT: &str = "0x400000000000";
+
+const PROGRAMS: &[&str] = &["hello"];
+
+fn main() {
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR not set"));
+    for program in PROGRAMS {
+        let source = format!("src/programs/{}.S", program);
+        let object = out_dir.join(format!("{}.o", program));
+        let binary = out_dir.join(format!("{}.elf", program));
+        println!("cargo:rerun-if-changed={}", source);
+
+        run(Command::new("as").arg(&source).arg("-o").arg(&object));
+        run(Command::new("ld")
+            .args(&["-static", "-nostdlib", "-s", "-z", "max-page-size=4096"])
+            .arg(format!("-Ttext-segment={}", TEXT_SEGMENT))
+            .arg(&object)
+            .arg("-o")
+            .arg(&binary));
+    }
+}
+
+fn run(command: &mut Command) {
+    let status = command
+        .status()
+        .unwrap_or_else(|error| panic!("failed to run {:?}: {}", command, error));
+    assert!(status.success(), "{:?} failed with {}", command, status);
+}
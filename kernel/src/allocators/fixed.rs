@@ -1,6 +1,11 @@
-use crate::allocators::Locked;
+use crate::{
+    allocators::Locked,
+    kalloc,
+    ktask::accounting::{self, Owner},
+};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr, ptr::NonNull};
+use x86_64::instructions::interrupts;
 
 /// The block sizes to use.
 ///
@@ -8,6 +13,12 @@ use core::{mem, ptr, ptr::NonNull};
 /// the block alignment (alignments must be always powers of 2).
 const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
+/// Every allocation starts at a multiple of this, the smallest block size.
+const TAG_GRANULE: usize = 8;
+
+/// The largest heap the allocator can keep track of tags for.
+const MAX_HEAP_SIZE: usize = kalloc::KERNEL_HEAP_INIT_SIZE as usize;
+
 struct Node {
     next: Option<&'static mut Node>,
 }
@@ -15,6 +26,13 @@ struct Node {
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut Node>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    /// Bytes handed out and not freed yet, rounded up to the block size.
+    used: usize,
+    heap_start: usize,
+    /// One bit per `TAG_GRANULE` bytes of heap, set where an allocation
+    /// with an owner tag starts. Only allocations made while a task is
+    /// polled carry one, see `ktask::accounting`.
+    tagged: [u64; MAX_HEAP_SIZE / TAG_GRANULE / 64],
 }
 
 impl FixedSizeBlockAllocator {
@@ -23,6 +41,9 @@ impl FixedSizeBlockAllocator {
         FixedSizeBlockAllocator {
             list_heads: [None; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            used: 0,
+            heap_start: 0,
+            tagged: [0; MAX_HEAP_SIZE / TAG_GRANULE / 64],
         }
     }
 
@@ -32,34 +53,32 @@ impl FixedSizeBlockAllocator {
     /// heap bounds are valid and that the heap is unused. This method must be
     /// called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        assert!(
+            heap_size <= MAX_HEAP_SIZE,
+            "heap too large for the tag bitmap"
+        );
+        self.heap_start = heap_start;
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
-    /// Allocates using the fallback allocator.
-    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
-        }
+    /// Size of the heap in bytes.
+    pub fn size(&self) -> usize {
+        self.fallback_allocator.size()
     }
-}
 
-/// Choose an appropriate block size for the given layout.
-///
-/// Returns an index into the `BLOCK_SIZES` array.
-fn list_index(layout: &Layout) -> Option<usize> {
-    let required_block_size = layout.size().max(layout.align());
-    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
-}
+    /// Bytes in use. Free blocks kept for reuse don't count.
+    pub fn used(&self) -> usize {
+        self.used
+    }
 
-unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
+    /// Allocates a block for `layout`, marked as carrying an owner tag
+    /// at its end if `tagged` is set.
+    fn allocate(&mut self, layout: Layout, tagged: bool) -> *mut u8 {
+        let ptr = match list_index(&layout) {
             Some(index) => {
-                match allocator.list_heads[index].take() {
+                match self.list_heads[index].take() {
                     Some(node) => {
-                        allocator.list_heads[index] = node.next.take();
+                        self.list_heads[index] = node.next.take();
                         node as *mut Node as *mut u8
                     }
                     None => {
@@ -68,32 +87,158 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                         // only works if all block sizes are a power of 2
                         let block_align = block_size;
                         let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                        allocator.fallback_alloc(layout)
+                        self.fallback_alloc(layout)
                     }
                 }
             }
-            None => allocator.fallback_alloc(layout),
+            None => self.fallback_alloc(layout),
+        };
+        if !ptr.is_null() {
+            self.used += allocated_size(&layout);
+            if tagged {
+                let (word, bit) = self.tag_bit(ptr);
+                self.tagged[word] |= bit;
+            }
         }
+        ptr
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let mut allocator = self.lock();
+    /// Whether the allocation at `ptr` carries an owner tag, forgetting it.
+    fn take_tagged(&mut self, ptr: *mut u8) -> bool {
+        let (word, bit) = self.tag_bit(ptr);
+        let tagged = self.tagged[word] & bit != 0;
+        self.tagged[word] &= !bit;
+        tagged
+    }
+
+    fn tag_bit(&self, ptr: *mut u8) -> (usize, u64) {
+        let index = (ptr as usize - self.heap_start) / TAG_GRANULE;
+        (index / 64, 1 << (index % 64))
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        self.used -= allocated_size(&layout);
         match list_index(&layout) {
             Some(index) => {
                 let new_node = Node {
-                    next: allocator.list_heads[index].take(),
+                    next: self.list_heads[index].take(),
                 };
                 // verify that block has size and alignment required for storing node
                 assert!(mem::size_of::<Node>() <= BLOCK_SIZES[index]);
                 assert!(mem::align_of::<Node>() <= BLOCK_SIZES[index]);
                 let new_node_ptr = ptr as *mut Node;
                 new_node_ptr.write(new_node);
-                allocator.list_heads[index] = Some(&mut *new_node_ptr);
+                self.list_heads[index] = Some(&mut *new_node_ptr);
             }
             None => {
                 let ptr = NonNull::new(ptr).unwrap();
-                allocator.fallback_allocator.deallocate(ptr, layout);
+                self.fallback_allocator.deallocate(ptr, layout);
             }
         }
     }
+
+    /// Gives every free block back to the fallback allocator, so that they
+    /// can merge into larger free areas. Returns the bytes released.
+    pub fn release_free_blocks(&mut self) -> usize {
+        let mut released = 0;
+        for (index, &block_size) in BLOCK_SIZES.iter().enumerate() {
+            let layout = Layout::from_size_align(block_size, block_size).unwrap();
+            while let Some(node) = self.list_heads[index].take() {
+                self.list_heads[index] = node.next.take();
+                let ptr = NonNull::from(node).cast();
+                unsafe { self.fallback_allocator.deallocate(ptr, layout) };
+                released += block_size;
+            }
+        }
+        released
+    }
+
+    /// Allocates using the fallback allocator.
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        match self.fallback_allocator.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+/// Choose an appropriate block size for the given layout.
+///
+/// Returns an index into the `BLOCK_SIZES` array.
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+/// How many bytes an allocation of `layout` takes.
+fn allocated_size(layout: &Layout) -> usize {
+    match list_index(layout) {
+        Some(index) => BLOCK_SIZES[index],
+        None => layout.size(),
+    }
+}
+
+/// The layout of an allocation of `layout` followed by the tag naming its
+/// owner, and the offset of the tag.
+fn tagged_layout(layout: &Layout) -> Option<(Layout, usize)> {
+    let (tagged, offset) = layout.extend(Layout::new::<Owner>()).ok()?;
+    Some((tagged.pad_to_align(), offset))
+}
+
+impl Locked<FixedSizeBlockAllocator> {
+    /// Allocates, running the shrinkers if the heap is full.
+    fn allocate_or_shrink(&self, layout: Layout, tagged: bool) -> *mut u8 {
+        // the timer may switch threads at any point, and a thread switched
+        // out while holding the lock would deadlock everybody else.
+        let ptr = interrupts::without_interrupts(|| self.lock().allocate(layout, tagged));
+        // the shrinkers free memory, so the lock must not be held for them
+        if ptr.is_null() && kalloc::shrink(allocated_size(&layout)) > 0 {
+            return interrupts::without_interrupts(|| self.lock().allocate(layout, tagged));
+        }
+        ptr
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // nobody to charge, so no need for a tag
+        if !accounting::charging() {
+            return self.allocate_or_shrink(layout, false);
+        }
+
+        let (layout, tag) = match tagged_layout(&layout) {
+            Some(tagged) => tagged,
+            None => return ptr::null_mut(),
+        };
+        let size = allocated_size(&layout);
+        let owner = match accounting::charge(size) {
+            Ok(owner) => owner,
+            // over the limit of the current task
+            Err(()) => return ptr::null_mut(),
+        };
+        let ptr = self.allocate_or_shrink(layout, true);
+        if ptr.is_null() {
+            accounting::uncharge(owner, size);
+        } else {
+            (ptr.add(tag) as *mut Owner).write(owner);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // see `alloc`
+        let (owner, size) = interrupts::without_interrupts(|| {
+            let mut allocator = self.lock();
+            if !allocator.take_tagged(ptr) {
+                allocator.deallocate(ptr, layout);
+                return (Owner::NONE, 0);
+            }
+            let (layout, tag) = tagged_layout(&layout).expect("freeing an impossible layout");
+            let owner = (ptr.add(tag) as *const Owner).read();
+            allocator.deallocate(ptr, layout);
+            (owner, allocated_size(&layout))
+        });
+        // after unlocking, this may free the owner's counters
+        accounting::uncharge(owner, size);
+    }
 }
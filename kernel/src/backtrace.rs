@@ -0,0 +1,87 @@
+//! Stack traces from the chain of saved frame pointers.
+//!
+//! Every function saves the caller's `rbp` at `[rbp]` with its return
+//! address above it, see `eliminate-frame-pointer` in the target spec. An
+//! interrupt handler's frame holds the interrupted code's `rbp` and `rip`
+//! the same way, so traces taken in handlers continue into the code they
+//! interrupted. Only addresses are printed; `addr2line` turns them into
+//! source lines.
+//!
+//! Frame pointers are only checked for plausibility, as walking must not
+//! take locks. Every frame must lie between the stack pointer and the top
+//! of the current thread's stack, which is mapped, so a corrupt chain, or
+//! code that uses `rbp` for something else, ends the walk instead of
+//! faulting.
+
+use crate::thread::scheduler;
+use core::fmt;
+
+/// Frames printed at most.
+const MAX_FRAMES: usize = 16;
+
+/// The call stack from where `Backtrace::here` was called.
+#[derive(Debug, Clone, Copy)]
+pub struct Backtrace {
+    rbp: u64,
+    /// Frames must lie in `bottom..top`.
+    bottom: u64,
+    top: u64,
+}
+
+impl Backtrace {
+    #[inline(always)]
+    pub fn here() -> Backtrace {
+        let (rbp, rsp): (u64, u64);
+        unsafe {
+            asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack));
+            asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack));
+        }
+        let (bottom, top) = scheduler::current_stack_bounds();
+        // not on the thread's stack, e.g. on an interrupt stack: the frames
+        // can't be checked, so there are none
+        let top = if bottom <= rsp && rsp < top { top } else { rsp };
+        Backtrace {
+            rbp,
+            bottom: rsp,
+            top,
+        }
+    }
+
+    /// Calls `f` with the return address of every frame, innermost first.
+    pub fn for_each_frame(&self, mut f: impl FnMut(u64)) {
+        let mut rbp = self.rbp;
+        for _ in 0..MAX_FRAMES {
+            // the frame is two words: the saved rbp and the return address
+            if rbp % 8 != 0 || rbp < self.bottom || rbp + 16 > self.top {
+                break;
+            }
+            let (next, return_address) = unsafe {
+                let frame = rbp as *const u64;
+                (*frame, *frame.add(1))
+            };
+            if return_address == 0 {
+                break;
+            }
+            f(return_address);
+            // frames of callers are higher up the stack
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut result = Ok(());
+        let mut index = 0;
+        self.for_each_frame(|address| {
+            if result.is_ok() {
+                result = write!(f, "\n  #{:<2} {:#018x}", index, address);
+            }
+            index += 1;
+        });
+        result
+    }
+}
@@ -0,0 +1,181 @@
+//! What the CPU we run on supports, as reported by CPUID.
+
+use conquer_once::spin::OnceCell;
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count, CpuidResult},
+    fmt, str,
+};
+
+#[derive(Clone, Copy)]
+pub struct CpuFeatures {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+
+    pub fxsr: bool,
+    pub sse: bool,
+    pub sse2: bool,
+    pub avx: bool,
+    pub xsave: bool,
+
+    pub apic: bool,
+    pub x2apic: bool,
+    pub pat: bool,
+    pub tsc_deadline: bool,
+
+    pub nx: bool,
+    pub smep: bool,
+    pub smap: bool,
+    pub pcid: bool,
+    pub invpcid: bool,
+    pub page_1gb: bool,
+
+    pub rdrand: bool,
+}
+
+static FEATURES: OnceCell<CpuFeatures> = OnceCell::uninit();
+
+/// The features of the boot CPU, queried on first use.
+pub fn features() -> &'static CpuFeatures {
+    FEATURES.init_once(CpuFeatures::detect);
+    FEATURES.try_get().expect("just initialized")
+}
+
+fn bit(register: u32, bit: u32) -> bool {
+    register & (1 << bit) != 0
+}
+
+impl CpuFeatures {
+    fn detect() -> Self {
+        let leaf0 = unsafe { __cpuid(0) };
+        let max_leaf = leaf0.eax;
+        let mut vendor = [0; 12];
+        vendor[0..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+        vendor[8..12].copy_from_slice(&leaf0.ecx.to_le_bytes());
+
+        let leaf1 = unsafe { __cpuid(1) };
+        let leaf7 = if max_leaf >= 7 {
+            unsafe { __cpuid_count(7, 0) }
+        } else {
+            CpuidResult {
+                eax: 0,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            }
+        };
+
+        let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+        let extended = if max_extended >= 0x8000_0001 {
+            unsafe { __cpuid(0x8000_0001) }.edx
+        } else {
+            0
+        };
+        let mut brand = [0; 48];
+        if max_extended >= 0x8000_0004 {
+            for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+                let result = unsafe { __cpuid(leaf) };
+                let registers = [result.eax, result.ebx, result.ecx, result.edx];
+                for (j, register) in registers.iter().enumerate() {
+                    let offset = i * 16 + j * 4;
+                    brand[offset..offset + 4].copy_from_slice(&register.to_le_bytes());
+                }
+            }
+        }
+
+        let signature = leaf1.eax;
+        let base_family = (signature >> 8) & 0xf;
+        let mut family = base_family;
+        let mut model = (signature >> 4) & 0xf;
+        if base_family == 0xf {
+            family += (signature >> 20) & 0xff;
+        }
+        if base_family == 0x6 || base_family == 0xf {
+            model |= ((signature >> 16) & 0xf) << 4;
+        }
+
+        CpuFeatures {
+            vendor,
+            brand,
+            family,
+            model,
+            stepping: signature & 0xf,
+
+            fxsr: bit(leaf1.edx, 24),
+            sse: bit(leaf1.edx, 25),
+            sse2: bit(leaf1.edx, 26),
+            avx: bit(leaf1.ecx, 28),
+            xsave: bit(leaf1.ecx, 26),
+
+            apic: bit(leaf1.edx, 9),
+            x2apic: bit(leaf1.ecx, 21),
+            pat: bit(leaf1.edx, 16),
+            tsc_deadline: bit(leaf1.ecx, 24),
+
+            nx: bit(extended, 20),
+            smep: bit(leaf7.ebx, 7),
+            smap: bit(leaf7.ebx, 20),
+            pcid: bit(leaf1.ecx, 17),
+            invpcid: bit(leaf7.ebx, 10),
+            page_1gb: bit(extended, 26),
+
+            rdrand: bit(leaf1.ecx, 30),
+        }
+    }
+
+    /// e.g. "GenuineIntel" or "AuthenticAMD".
+    pub fn vendor(&self) -> &str {
+        str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+
+    /// The marketing name of the CPU, empty if it doesn't report one.
+    pub fn brand(&self) -> &str {
+        let len = self
+            .brand
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(self.brand.len());
+        str::from_utf8(&self.brand[..len]).unwrap_or("").trim()
+    }
+}
+
+impl fmt::Display for CpuFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} {} (family {:#x}, model {:#x}, stepping {})",
+            self.vendor(),
+            self.brand(),
+            self.family,
+            self.model,
+            self.stepping
+        )?;
+
+        let flags = [
+            ("apic", self.apic),
+            ("x2apic", self.x2apic),
+            ("tsc-deadline", self.tsc_deadline),
+            ("pat", self.pat),
+            ("nx", self.nx),
+            ("smep", self.smep),
+            ("smap", self.smap),
+            ("pcid", self.pcid),
+            ("invpcid", self.invpcid),
+            ("1g-pages", self.page_1gb),
+            ("rdrand", self.rdrand),
+            ("sse2", self.sse2),
+            ("xsave", self.xsave),
+            ("avx", self.avx),
+        ];
+        write!(f, "features:")?;
+        for &(name, supported) in flags.iter() {
+            if supported {
+                write!(f, " {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,7 @@
+mod features;
+
+pub use features::{features, CpuFeatures};
+
 pub fn forever_hlt() -> ! {
     loop {
         hlt();
@@ -0,0 +1,284 @@
+//! Buffers for devices that access memory directly.
+//!
+//! A `DmaBuffer` is physically contiguous, so a device can be handed its
+//! physical address, and has a kernel mapping of its own with the caching
+//! the device needs. The CPU must never see the same memory with two memory
+//! types, so while the buffer lives, its frames get the same caching in the
+//! physical memory mapping too.
+
+use crate::{cpu, memory};
+use core::{
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr,
+};
+use x86_64::{
+    registers::model_specific::Msr,
+    structures::paging::{
+        FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const IA32_PAT: u32 = 0x277;
+
+// memory types in the PAT
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WB: u64 = 0x06;
+const PAT_UC_MINUS: u64 = 0x07;
+
+/// Like the power-on default, except that entry 1 (PWT alone) is
+/// write-combining instead of write-through. Entries 4 to 7 need the PAT
+/// bit, which we never set.
+const PAT: u64 = PAT_WB
+    | PAT_WC << 8
+    | PAT_UC_MINUS << 16
+    | PAT_UC << 24
+    | PAT_WB << 32
+    | PAT_WT << 40
+    | PAT_UC_MINUS << 48
+    | PAT_UC << 56;
+
+/// Programs the PAT so that write-combining mappings are possible.
+/// Without PAT support, write-combining falls back to uncacheable.
+pub fn init() {
+    if !cpu::features().pat {
+        return;
+    }
+    unsafe {
+        Msr::new(IA32_PAT).write(PAT);
+        // nothing is mapped write-through yet, but drop whatever the
+        // caches and TLB know about the old memory types anyway
+        asm!("wbinvd", options(nostack));
+    }
+    x86_64::instructions::tlb::flush_all();
+}
+
+/// How the CPU caches accesses to a buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Normal memory, for devices that snoop the caches.
+    WriteBack,
+    /// Every access goes to memory, in order.
+    Uncacheable,
+    /// Writes are combined into bursts and may be reordered, e.g. for
+    /// framebuffers. Reads are uncached.
+    WriteCombining,
+}
+
+impl CacheMode {
+    /// The page table flags selecting this mode.
+    pub(crate) fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteCombining if cpu::features().pat => PageTableFlags::WRITE_THROUGH,
+            CacheMode::Uncacheable | CacheMode::WriteCombining => {
+                PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+            }
+        }
+    }
+}
+
+/// Constraints a device puts on a buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct DmaOptions {
+    /// The buffer must end at or below this physical address, e.g.
+    /// `1 << 32` for devices with 32-bit addressing.
+    pub limit: u64,
+    /// Alignment of the physical address, a power of two of at least 4096.
+    pub align: u64,
+    pub cache: CacheMode,
+}
+
+impl Default for DmaOptions {
+    fn default() -> Self {
+        DmaOptions {
+            limit: u64::MAX,
+            align: Size4KiB::SIZE,
+            cache: CacheMode::Uncacheable,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    /// No physically contiguous memory meets the constraints.
+    OutOfMemory,
+    /// No room left in the kernel's address space for the mapping.
+    OutOfAddressSpace,
+}
+
+/// A `T` in physically contiguous memory. Unmapped and freed on drop.
+pub struct DmaBuffer<T> {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    frames: usize,
+    cache: CacheMode,
+    /// Whether the `T` was written, i.e. whether drop must drop it.
+    initialized: bool,
+    _value: PhantomData<T>,
+}
+
+// a `DmaBuffer` owns its `T` just like a `Box`
+unsafe impl<T: Send> Send for DmaBuffer<T> {}
+unsafe impl<T: Sync> Sync for DmaBuffer<T> {}
+
+impl<T> DmaBuffer<T> {
+    /// Moves `value` into a new buffer.
+    pub fn new(value: T, options: DmaOptions) -> Result<Self, DmaError> {
+        let mut buffer = Self::allocate(options)?;
+        unsafe { ptr::write(buffer.virt.as_mut_ptr(), value) };
+        buffer.initialized = true;
+        Ok(buffer)
+    }
+
+    /// A buffer filled with zeros. Big buffers should be created this way
+    /// rather than moved in by `new`, which puts the value on the stack first.
+    ///
+    /// # Safety
+    ///
+    /// All zeros must be a valid `T`.
+    pub unsafe fn zeroed(options: DmaOptions) -> Result<Self, DmaError> {
+        let mut buffer = Self::allocate(options)?;
+        ptr::write_bytes(buffer.virt.as_mut_ptr::<u8>(), 0, buffer.size());
+        buffer.initialized = true;
+        Ok(buffer)
+    }
+
+    fn allocate(options: DmaOptions) -> Result<Self, DmaError> {
+        assert!(
+            options.align.is_power_of_two() && options.align >= Size4KiB::SIZE,
+            "bad DMA alignment"
+        );
+        assert!(mem::align_of::<T>() as u64 <= Size4KiB::SIZE);
+        let size = (mem::size_of::<T>().max(1) as u64 + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1);
+        let frames = (size / Size4KiB::SIZE) as usize;
+
+        let virt =
+            memory::huge::reserve(size, Size4KiB::SIZE).ok_or(DmaError::OutOfAddressSpace)?;
+        let first =
+            memory::frame_allocator().allocate_contiguous(frames, options.align, options.limit);
+        let first = match first {
+            Some(frame) => frame,
+            None => {
+                memory::huge::unreserve(virt);
+                return Err(DmaError::OutOfMemory);
+            }
+        };
+
+        // from here on, drop frees everything
+        let buffer: Self = DmaBuffer {
+            virt,
+            phys: first.start_address(),
+            frames,
+            cache: options.cache,
+            initialized: false,
+            _value: PhantomData,
+        };
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_EXECUTE
+            | options.cache.flags();
+        let mut page_table = memory::kernel_page_table();
+        let mut frame_allocator = memory::frame_allocator();
+        for (page, frame) in buffer.pages().zip(buffer.phys_frames()) {
+            let mapped = unsafe { page_table.map_to(page, frame, flags, &mut *frame_allocator) };
+            match mapped {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    // drop takes the locks again
+                    drop(frame_allocator);
+                    drop(page_table);
+                    return Err(DmaError::OutOfMemory);
+                }
+            }
+        }
+        drop(frame_allocator);
+        drop(page_table);
+
+        if options.cache != CacheMode::WriteBack {
+            memory::huge::set_physical_cache(first, frames as u64, options.cache.flags())
+                .map_err(|_| DmaError::OutOfMemory)?;
+            // lines cached through the write-back alias before would be
+            // written back behind the device's back
+            unsafe { asm!("wbinvd", options(nostack)) };
+        }
+        Ok(buffer)
+    }
+
+    /// What to program into the device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    /// Bytes mapped, `size_of::<T>()` rounded up to whole pages.
+    pub fn size(&self) -> usize {
+        self.frames * Size4KiB::SIZE as usize
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let first = Page::containing_address(self.virt);
+        Page::range(first, first + self.frames as u64)
+    }
+
+    fn phys_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        let first = PhysFrame::containing_address(self.phys);
+        PhysFrame::range(first, first + self.frames as u64)
+    }
+}
+
+impl<T> Deref for DmaBuffer<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.virt.as_ptr() }
+    }
+}
+
+impl<T> DerefMut for DmaBuffer<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.virt.as_mut_ptr() }
+    }
+}
+
+impl<T> Drop for DmaBuffer<T> {
+    fn drop(&mut self) {
+        if self.initialized {
+            unsafe { ptr::drop_in_place(self.virt.as_mut_ptr::<T>()) };
+        }
+
+        let mut page_table = memory::kernel_page_table();
+        for page in self.pages() {
+            // pages may be missing if `allocate` failed half way
+            if let Ok((_, flush)) = page_table.unmap(page) {
+                flush.flush();
+            }
+        }
+        drop(page_table);
+
+        // whoever gets the frames next expects normal memory
+        if self.cache != CacheMode::WriteBack {
+            let first = PhysFrame::containing_address(self.phys);
+            memory::huge::set_physical_cache(
+                first,
+                self.frames as u64,
+                CacheMode::WriteBack.flags(),
+            )
+            .expect("going back to write-back allocates nothing");
+        }
+
+        let mut frame_allocator = memory::frame_allocator();
+        for frame in self.phys_frames() {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+        drop(frame_allocator);
+        memory::huge::unreserve(self.virt);
+    }
+}
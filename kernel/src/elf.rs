@@ -0,0 +1,170 @@
+//! Just enough of ELF64 to load statically linked x86_64 executables.
+
+use core::{convert::TryInto, mem::size_of, ptr};
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_PHDR: u32 = 6;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The image doesn't start with the ELF magic.
+    NotElf,
+    /// A valid ELF file we can't run, e.g. 32-bit or not x86_64.
+    Unsupported(&'static str),
+    /// Headers pointing outside the image or otherwise inconsistent.
+    Malformed(&'static str),
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct FileHeader {
+    pub ident: [u8; 16],
+    pub elf_type: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    /// The bytes of this segment stored in the file.
+    pub fn file_range(&self) -> core::ops::Range<usize> {
+        self.offset as usize..(self.offset + self.filesz) as usize
+    }
+}
+
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    pub header: FileHeader,
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        let file = Self::parse_header(data)?;
+        for ph in file.program_headers() {
+            let ph = ph?;
+            if ph.p_type == PT_LOAD {
+                if ph.filesz > ph.memsz {
+                    return Err(ElfError::Malformed("segment file size exceeds memory size"));
+                }
+                let end = ph.offset.checked_add(ph.filesz);
+                if end.map_or(true, |end| end > data.len() as u64) {
+                    return Err(ElfError::Malformed("segment outside of file"));
+                }
+            }
+        }
+        Ok(file)
+    }
+
+    /// Only checks the file header, for images whose segments are not
+    /// part of `data`, e.g. the already loaded kernel.
+    pub fn parse_header(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < 4 || data[..4] != ELF_MAGIC {
+            return Err(ElfError::NotElf);
+        }
+        let header: FileHeader = read(data, 0).ok_or(ElfError::Malformed("truncated header"))?;
+
+        if header.ident[4] != ELFCLASS64 {
+            return Err(ElfError::Unsupported("not a 64-bit object"));
+        }
+        if header.ident[5] != ELFDATA2LSB {
+            return Err(ElfError::Unsupported("not little endian"));
+        }
+        if header.ident[6] != EV_CURRENT {
+            return Err(ElfError::Unsupported("unknown ELF version"));
+        }
+        if header.elf_type != ET_EXEC {
+            return Err(ElfError::Unsupported("not a static executable"));
+        }
+        if header.machine != EM_X86_64 {
+            return Err(ElfError::Unsupported("not an x86_64 executable"));
+        }
+        if header.phentsize as usize != size_of::<ProgramHeader>() {
+            return Err(ElfError::Malformed("bad program header size"));
+        }
+
+        Ok(ElfFile { data, header })
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = Result<ProgramHeader, ElfError>> + 'a {
+        let data = self.data;
+        let phoff = self.header.phoff;
+        (0..self.header.phnum as u64).map(move |i| {
+            let offset = phoff
+                .checked_add(i * size_of::<ProgramHeader>() as u64)
+                .and_then(|offset| offset.try_into().ok())
+                .ok_or(ElfError::Malformed("program header offset overflow"))?;
+            read(data, offset).ok_or(ElfError::Malformed("program header outside of file"))
+        })
+    }
+
+    /// Segments that must be mapped into memory.
+    pub fn load_segments(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        // `parse` already validated every program header.
+        self.program_headers()
+            .filter_map(Result::ok)
+            .filter(|ph| ph.p_type == PT_LOAD)
+    }
+
+    /// Virtual address of the program headers once loaded,
+    /// needed for the `AT_PHDR` auxiliary vector entry.
+    pub fn program_headers_vaddr(&self) -> Option<u64> {
+        if let Some(phdr) = self
+            .program_headers()
+            .filter_map(Result::ok)
+            .find(|ph| ph.p_type == PT_PHDR)
+        {
+            return Some(phdr.vaddr);
+        }
+
+        let phoff = self.header.phoff;
+        self.load_segments()
+            .find(|ph| ph.offset <= phoff && phoff < ph.offset + ph.filesz)
+            .map(|ph| ph.vaddr + (phoff - ph.offset))
+    }
+}
+
+/// Reads a `T` at `offset`, the image has no alignment guarantees.
+fn read<T: Copy>(data: &[u8], offset: usize) -> Option<T> {
+    let end = offset.checked_add(size_of::<T>())?;
+    if end > data.len() {
+        return None;
+    }
+    Some(unsafe { ptr::read_unaligned(data[offset..].as_ptr() as *const T) })
+}
@@ -0,0 +1,247 @@
+//! Lazy saving of x87/SSE/AVX state for user code.
+//!
+//! The kernel itself is built with soft-float and never touches these
+//! registers, so they only ever hold user state. Instead of saving them on
+//! every thread switch, the scheduler sets CR0.TS: the next FPU/SSE
+//! instruction raises #NM, and only then the registers are saved to the
+//! thread that last used them and loaded from the current thread.
+//! Each program starts with a fresh state that is freed when it exits.
+
+use crate::cpu;
+use alloc::{vec, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::{
+    arch::x86_64::__cpuid_count,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+// state components in XCR0
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+/// Size of the FXSAVE area.
+const LEGACY_AREA_SIZE: usize = 512;
+
+/// Control words after `fninit`, with every exception masked.
+const DEFAULT_FCW: u16 = 0x037f;
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+#[derive(Debug, Clone, Copy)]
+pub struct FpuSupport {
+    /// Whether XSAVE is used instead of FXSAVE.
+    pub xsave: bool,
+    pub avx: bool,
+    /// Bytes needed to save the state of a thread.
+    pub area_size: usize,
+}
+
+static SUPPORT: OnceCell<Option<FpuSupport>> = OnceCell::uninit();
+
+/// The state whose contents currently live in the registers.
+static OWNER: AtomicPtr<FpuState> = AtomicPtr::new(ptr::null_mut());
+
+/// Enables SSE (and AVX, if present) if the CPU supports it.
+/// Without at least FXSR and SSE2, user code gets #NM on the first
+/// floating point instruction and is killed.
+pub fn init() {
+    SUPPORT.init_once(|| unsafe { enable() });
+}
+
+/// What `init` enabled, `None` if floating point is unavailable.
+pub fn support() -> Option<FpuSupport> {
+    SUPPORT.try_get().ok().copied().flatten()
+}
+
+unsafe fn enable() -> Option<FpuSupport> {
+    let features = cpu::features();
+    if !(features.fxsr && features.sse && features.sse2) {
+        Cr0::update(|flags| flags.insert(Cr0Flags::EMULATE_COPROCESSOR));
+        return None;
+    }
+
+    // TS makes the very first use trap as well, so that it gets an owner
+    Cr0::update(|flags| {
+        flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+        flags.insert(
+            Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR | Cr0Flags::TASK_SWITCHED,
+        );
+    });
+    Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+
+    if !features.xsave {
+        return Some(FpuSupport {
+            xsave: false,
+            avx: false,
+            area_size: LEGACY_AREA_SIZE,
+        });
+    }
+
+    Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE));
+    let avx = features.avx;
+    let mut xcr0 = XCR0_X87 | XCR0_SSE;
+    if avx {
+        xcr0 |= XCR0_AVX;
+    }
+    asm!(
+        "xsetbv",
+        in("ecx") 0,
+        in("eax") xcr0 as u32,
+        in("edx") (xcr0 >> 32) as u32,
+        options(nomem, nostack)
+    );
+
+    // EBX of leaf 0xd is the size needed for the components now in XCR0
+    let area_size = __cpuid_count(0xd, 0).ebx as usize;
+    Some(FpuSupport {
+        xsave: true,
+        avx,
+        area_size,
+    })
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+struct Block([u8; 64]);
+
+/// Saved FPU/SSE/AVX registers of one thread.
+pub struct FpuState {
+    /// Empty for threads that don't run user code.
+    area: Vec<Block>,
+}
+
+impl FpuState {
+    /// No state at all, for threads that never run user code. Using the
+    /// registers with it raises #NM like without floating point support.
+    pub const fn empty() -> Self {
+        FpuState { area: Vec::new() }
+    }
+
+    /// The state right after `fninit`, with empty vector registers.
+    pub fn new() -> Self {
+        let size = support().map_or(0, |support| support.area_size);
+        let mut state = FpuState {
+            area: vec![Block([0; 64]); (size + 63) / 64],
+        };
+        if size == 0 {
+            return state;
+        }
+
+        let bytes = state.bytes_mut();
+        bytes[0..2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+        bytes[24..28].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        if support().map_or(false, |support| support.xsave) {
+            // XSTATE_BV in the XSAVE header: take x87 and SSE from the
+            // legacy area above, everything else starts out zeroed.
+            let bv = XCR0_X87 | XCR0_SSE;
+            bytes[LEGACY_AREA_SIZE..LEGACY_AREA_SIZE + 8].copy_from_slice(&bv.to_le_bytes());
+        }
+        state
+    }
+
+    /// Puts `other` in place of `self` and returns the old state. Unlike
+    /// assigning, this makes sure the registers aren't taken for `other`,
+    /// so a new program never sees what the last one left in them.
+    pub fn replace(&mut self, other: FpuState) -> FpuState {
+        self.disown();
+        core::mem::replace(self, other)
+    }
+
+    /// If the registers hold `self`, makes them belong to nobody. The next
+    /// use traps and loads the state of whoever runs then.
+    fn disown(&mut self) {
+        let disowned = OWNER
+            .compare_exchange(
+                self as *mut FpuState,
+                ptr::null_mut(),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_ok();
+        if disowned {
+            unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED)) };
+        }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        let len = self.area.len() * core::mem::size_of::<Block>();
+        unsafe { core::slice::from_raw_parts_mut(self.area.as_mut_ptr() as *mut u8, len) }
+    }
+
+    unsafe fn save(&mut self, xsave: bool) {
+        let area = self.area.as_mut_ptr();
+        if xsave {
+            asm!("xsave64 [{}]", in(reg) area, in("eax") !0u32, in("edx") !0u32, options(nostack));
+        } else {
+            asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+        }
+    }
+
+    unsafe fn restore(&self, xsave: bool) {
+        let area = self.area.as_ptr();
+        if xsave {
+            asm!("xrstor64 [{}]", in(reg) area, in("eax") !0u32, in("edx") !0u32, options(nostack));
+        } else {
+            asm!("fxrstor64 [{}]", in(reg) area, options(nostack));
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        // the registers belong to nobody now, there is nothing to save
+        self.disown();
+    }
+}
+
+/// Called by the scheduler right before switching to the thread owning
+/// `next`: the registers stay accessible only if they already hold its state.
+pub(crate) fn switch_to(next: &FpuState) {
+    if support().is_none() {
+        return;
+    }
+    let owned = ptr::eq(OWNER.load(Ordering::Relaxed), next);
+    unsafe {
+        Cr0::update(|flags| flags.set(Cr0Flags::TASK_SWITCHED, !owned));
+    }
+}
+
+/// Handles #NM for lazy switching: saves the registers to their previous
+/// owner and loads the state of the current thread.
+///
+/// Returns false if the exception wasn't caused by CR0.TS, or the current
+/// thread has no state because it doesn't run user code.
+pub(crate) fn device_not_available(current: impl FnOnce() -> *mut FpuState) -> bool {
+    let support = match support() {
+        Some(support) => support,
+        None => return false,
+    };
+    if !Cr0::read().contains(Cr0Flags::TASK_SWITCHED) {
+        return false;
+    }
+
+    unsafe {
+        let current = current();
+        if (*current).area.is_empty() {
+            return false;
+        }
+        Cr0::update(|flags| flags.remove(Cr0Flags::TASK_SWITCHED));
+        let owner = OWNER.load(Ordering::Relaxed);
+        if owner != current {
+            if let Some(owner) = owner.as_mut() {
+                owner.save(support.xsave);
+            }
+            (*current).restore(support.xsave);
+            OWNER.store(current, Ordering::Relaxed);
+        }
+    }
+    true
+}
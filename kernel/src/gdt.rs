@@ -1,3 +1,4 @@
+use crate::memory::stack::KernelStack;
 use lazy_static::lazy_static;
 use x86_64::{
     structures::{
@@ -8,48 +9,104 @@ use x86_64::{
 };
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
-}
+/// Size of each interrupt stack.
+const IST_STACK_SIZE: u64 = 16 * 1024;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+/// Size of the interrupt stacks used until `init_interrupt_stacks`.
+const BOOT_IST_STACK_SIZE: usize = 4096;
+
+const IST_STACKS: [(u16, &str); 3] = [
+    (DOUBLE_FAULT_IST_INDEX, "the double fault stack"),
+    (NMI_IST_INDEX, "the NMI stack"),
+    (MACHINE_CHECK_IST_INDEX, "the machine check stack"),
+];
+
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
 }
 
+/// The TSS is not constant: the CPU reads `privilege_stack_table[0]` (RSP0)
+/// every time it switches from ring 3 to ring 0, so we update it in place
+/// whenever the kernel stack of the running user code changes.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &TSS }));
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
         (
             gdt,
             Selectors {
                 code_selector,
                 tss_selector,
+                user_data_selector,
+                user_code_selector,
             },
         )
     };
 }
 
+/// Gives the exceptions with stacks of their own small static stacks, so
+/// that they can be reported before memory is set up.
+fn init_tss() {
+    static mut STACKS: [[u8; BOOT_IST_STACK_SIZE]; IST_STACKS.len()] =
+        [[0; BOOT_IST_STACK_SIZE]; IST_STACKS.len()];
+
+    for (stack, &(index, _)) in unsafe { STACKS.iter() }.zip(IST_STACKS.iter()) {
+        let stack_start = VirtAddr::from_ptr(stack);
+        unsafe { TSS.interrupt_stack_table[index as usize] = stack_start + BOOT_IST_STACK_SIZE };
+    }
+}
+
 pub fn init_gdt() {
     use x86_64::instructions::{segmentation::set_cs, tables::load_tss};
 
+    init_tss();
     GDT.0.load();
     unsafe {
         set_cs(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Gives the exceptions that can't trust the current stack guarded stacks
+/// of their own instead of the boot ones. Needs the kernel page table.
+/// The CPU only reads the slots when it delivers an exception, so this
+/// may run with the IDT loaded.
+pub fn init_interrupt_stacks() {
+    for &(index, name) in IST_STACKS.iter() {
+        let stack = KernelStack::new(name, IST_STACK_SIZE).expect("no memory for interrupt stacks");
+        unsafe { TSS.interrupt_stack_table[index as usize] = stack.top() };
+        // used until the machine goes down
+        core::mem::forget(stack);
+    }
+}
+
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+/// Sets the stack the CPU switches to when an interrupt, exception
+/// or system call arrives while running in ring 3.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        TSS.privilege_stack_table[0] = stack_top;
+    });
+}
+
+/// Raw pointer to RSP0 in the TSS, for assembly that needs to set it
+/// to the exact stack pointer it is running on.
+/// The TSS is packed, so we can't borrow the field; RSP0 lives right after
+/// the first reserved dword.
+pub(crate) fn kernel_stack_slot() -> *mut u64 {
+    unsafe { (&mut TSS as *mut TaskStateSegment as *mut u8).add(4) as *mut u64 }
+}
@@ -1,10 +1,18 @@
-use crate::{gdt, println};
+use crate::{
+    fpu::{self, FpuState},
+    gdt,
+    ktask::accounting::{self, Charging},
+    memory,
+    mmio::{PortRegister, ReadOnly},
+    println, syscall, thread, time, usermode,
+};
 use lazy_static::lazy_static;
 use pic8259_simple::ChainedPics;
 use spin;
 use x86_64::{
-    instructions::port::Port,
+    registers::rflags::RFlags,
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    PrivilegeLevel, VirtAddr,
 };
 
 pub const PIC_OFFSET_DELTA: u8 = 8;
@@ -22,15 +30,27 @@ lazy_static! {
         idt.divide_error.set_handler_fn(divide_error_handler);
         idt.device_not_available.set_handler_fn(device_not_available_handler);
         idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
 
         idt[Interrupts::Timer as usize].set_handler_fn(int_timer_handler);
         idt[Interrupts::Keyboard as usize].set_handler_fn(int_keyboard_handler);
-        idt[Interrupts::Syscall as usize].set_handler_fn(int_syscall_handler);
+        // user programs must be allowed to `int 0x80`
+        idt[Interrupts::Syscall as usize]
+            .set_handler_fn(syscall::handler())
+            .set_privilege_level(PrivilegeLevel::Ring3);
 
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt
+                .set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check
+                .set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
         }
         idt
     };
@@ -64,53 +84,155 @@ pub fn enable_interrupts() {
     x86_64::instructions::interrupts::enable()
 }
 
+/// Run first by every handler: clears AC, see `usermode::clear_user_access`,
+/// and keeps the interrupted task from being charged for allocations until
+/// the handler returns.
+#[inline(always)]
+fn enter_handler() -> Charging {
+    usermode::clear_user_access();
+    accounting::suspend()
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
+    let _charging = enter_handler();
     println!("Interrupted: breakpoint\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn divide_error_handler(stack_frame: &mut InterruptStackFrame) {
+    let _charging = enter_handler();
+    usermode::kill_on_user_fault(stack_frame, "divide error");
     println!("Interrupted: divide error\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn device_not_available_handler(stack_frame: &mut InterruptStackFrame) {
+    let _charging = enter_handler();
+    let current = || thread::scheduler::with_current(|thread| &mut thread.fpu as *mut FpuState);
+    if fpu::device_not_available(current) {
+        return;
+    }
+    usermode::kill_on_user_fault(stack_frame, "device not available");
     println!("Interrupted: device not available\n{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: &mut InterruptStackFrame) {
+    let _charging = enter_handler();
+    usermode::kill_on_user_fault(stack_frame, "x87 floating point exception");
+    println!(
+        "Interrupted: x87 floating point exception\n{:#?}",
+        stack_frame
+    );
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: &mut InterruptStackFrame) {
+    let _charging = enter_handler();
+    usermode::kill_on_user_fault(stack_frame, "SIMD floating point exception");
+    println!(
+        "Interrupted: SIMD floating point exception\n{:#?}",
+        stack_frame
+    );
+}
+
 extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: &mut InterruptStackFrame) {
+    let _charging = enter_handler();
+    usermode::kill_on_user_fault(stack_frame, "invalid opcode");
     println!("Interrupted: invalid opcode\n{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    let _charging = enter_handler();
+    usermode::kill_on_user_fault(stack_frame, "general protection fault");
+    panic!(
+        "Interrupted: general protection fault (error code {})\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
 extern "x86-interrupt" fn page_fault_handler(
-    _stack_frame: &mut InterruptStackFrame,
-    _error_code: PageFaultErrorCode,
+    stack_frame: &mut InterruptStackFrame,
+    error_code: PageFaultErrorCode,
 ) {
+    let _charging = enter_handler();
     let access = x86_64::registers::control::Cr2::read();
-    println!("Page fault when accessing {:?}", access);
+    if error_code
+        .contains(PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::PROTECTION_VIOLATION)
+    {
+        // copying a page takes the page table and frame allocator locks,
+        // which the interrupted code may only hold with interrupts enabled
+        if RFlags::from_bits_truncate(stack_frame.cpu_flags).contains(RFlags::INTERRUPT_FLAG) {
+            x86_64::instructions::interrupts::enable();
+        }
+        let handled = memory::cow::handle_write_fault(access);
+        x86_64::instructions::interrupts::disable();
+        if handled {
+            return;
+        }
+    }
+    if usermode::is_user_frame(stack_frame) {
+        println!(
+            "User page fault when accessing {:?} ({:?})",
+            access, error_code
+        );
+        usermode::kill_on_user_fault(stack_frame, "page fault");
+    }
+    check_stack_overflow(access);
+    // returning would just fault again
+    panic!(
+        "Page fault when accessing {:?} ({:?})\n{:#?}",
+        access, error_code, stack_frame
+    );
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut InterruptStackFrame,
     error_code: u64,
 ) -> ! {
+    let _charging = enter_handler();
+    // running into a guard page usually ends up here: the page fault
+    // can't push its frame onto the stack that overflowed.
+    check_stack_overflow(x86_64::registers::control::Cr2::read());
     panic!(
         "Interrupted: double fault (error code {})\n{:#?}",
         error_code, stack_frame
     );
 }
 
+extern "x86-interrupt" fn nmi_handler(stack_frame: &mut InterruptStackFrame) {
+    let _charging = enter_handler();
+    println!("Interrupted: non-maskable interrupt\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut InterruptStackFrame) -> ! {
+    let _charging = enter_handler();
+    panic!("Interrupted: machine check\n{:#?}", stack_frame);
+}
+
+/// Panics with a useful message if `access` hit the guard page of a stack.
+fn check_stack_overflow(access: VirtAddr) {
+    memory::stack::with_overflowed_stack(access, |name| {
+        panic!("stack overflow in {} (accessing {:?})", name, access)
+    });
+}
+
 extern "x86-interrupt" fn int_timer_handler(_stack_frame: &mut InterruptStackFrame) {
-    // TODO: setup a kernel timer
+    let _charging = enter_handler();
+    time::tick();
     Interrupts::Timer.end_of_interrupt();
+    crate::ktask::info::watchdog_tick();
+    // may switch to another thread, which is why the interrupt must
+    // already be acknowledged
+    thread::scheduler::timer_tick();
 }
 
+/// Data port of the PS/2 controller, holding the next scancode.
+const KEYBOARD_DATA: PortRegister<u8, ReadOnly> = unsafe { PortRegister::new(0x60) };
+
 extern "x86-interrupt" fn int_keyboard_handler(_stack_frame: &mut InterruptStackFrame) {
-    let mut port = Port::new(0x60);
-    let code = unsafe { port.read() };
+    let _charging = enter_handler();
+    let code = KEYBOARD_DATA.read();
 
     crate::ktask::kernel_tasks::keyboard::add_scancode(code);
     Interrupts::Keyboard.end_of_interrupt();
 }
-
-extern "x86-interrupt" fn int_syscall_handler(_stack_frame: &mut InterruptStackFrame) {
-    Interrupts::Syscall.end_of_interrupt()
-}
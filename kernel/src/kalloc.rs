@@ -1,11 +1,17 @@
+use alloc::{
+    alloc::{alloc, Layout},
+    boxed::Box,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
+    instructions::interrupts,
+    structures::paging::{mapper::MapToError, PageTableFlags, Size4KiB},
     VirtAddr,
 };
 
-use crate::allocators;
+use crate::{allocators, memory, thread};
 
 /// Virtual address of kernel heap start
 pub const KERNEL_HEAP_START: u64 = 0x_0000_7000_0000;
@@ -19,27 +25,12 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("kalloc error: {:?}", layout)
 }
 
-pub fn init_kernel_heap(
-    page_table: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
+/// Maps the heap with 2 MiB pages (it is 2 MiB aligned) and hands it to
+/// the allocator.
+pub fn init_kernel_heap() -> Result<(), MapToError<Size4KiB>> {
     let heap_start = VirtAddr::new(KERNEL_HEAP_START);
-    let heap_end = heap_start + KERNEL_HEAP_INIT_SIZE;
-    let heap_start_page = Page::containing_address(heap_start);
-    let heap_end_page = Page::containing_address(heap_end);
-
-    let range = Page::range(heap_start_page, heap_end_page);
-    for page in range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            page_table
-                .map_to(page, frame, flags, frame_allocator)?
-                .flush()
-        };
-    }
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    memory::huge::map_range(heap_start, KERNEL_HEAP_INIT_SIZE, flags)?;
 
     // initialize kernel allocator
     unsafe {
@@ -47,5 +38,119 @@ pub fn init_kernel_heap(
             .lock()
             .init(KERNEL_HEAP_START as usize, KERNEL_HEAP_INIT_SIZE as usize);
     }
+    register_shrinker("heap free lists", |_| {
+        interrupts::without_interrupts(|| {
+            allocators::KERNEL_ALLOCATOR3.lock().release_free_blocks()
+        })
+    });
     Ok(())
 }
+
+/// Bytes of the heap in use and its total size.
+pub fn heap_usage() -> (usize, usize) {
+    // the allocator is only ever locked with interrupts disabled
+    interrupts::without_interrupts(|| {
+        let allocator = allocators::KERNEL_ALLOCATOR3.lock();
+        (allocator.used(), allocator.size())
+    })
+}
+
+/// Frees memory that can be recreated later, e.g. caches, when the heap
+/// runs out. Gets the number of bytes still missing and returns how many
+/// it freed.
+///
+/// Runs in whatever context the allocation happened in, possibly with
+/// interrupts disabled, so it must not block. It may free but must not
+/// allocate or (un)register shrinkers.
+pub type Shrinker = fn(usize) -> usize;
+
+static SHRINKERS: Mutex<Vec<(&'static str, Shrinker)>> = Mutex::new(Vec::new());
+
+/// The thread running the shrinkers, so that a failing allocation inside
+/// one of them doesn't run them again. Other threads wait their turn.
+static SHRINKING: AtomicU64 = AtomicU64::new(NOBODY);
+
+const NOBODY: u64 = u64::MAX;
+
+/// Adds a shrinker that is called before an allocation fails.
+pub fn register_shrinker(name: &'static str, shrinker: Shrinker) {
+    SHRINKERS.lock().push((name, shrinker));
+}
+
+pub fn unregister_shrinker(name: &'static str) {
+    SHRINKERS.lock().retain(|&(other, _)| other != name);
+}
+
+/// Runs the shrinkers in registration order until `wanted` bytes are free.
+/// Returns how many bytes they freed.
+pub(crate) fn shrink(wanted: usize) -> usize {
+    let me = thread::current().as_u64();
+    loop {
+        if SHRINKING.load(Ordering::Acquire) == me {
+            return 0;
+        }
+        // never wait for the lock itself, the holder may be registering
+        // a shrinker and be the one allocating
+        if let Some(shrinkers) = SHRINKERS.try_lock() {
+            SHRINKING.store(me, Ordering::Relaxed);
+            let mut freed = 0;
+            for &(_, shrinker) in shrinkers.iter() {
+                freed += shrinker(wanted.saturating_sub(freed));
+                if freed >= wanted {
+                    break;
+                }
+            }
+            SHRINKING.store(NOBODY, Ordering::Release);
+            return freed;
+        }
+        // a thread preempted while shrinking finishes once it runs again,
+        // which needs the timer
+        if SHRINKING.load(Ordering::Relaxed) == NOBODY || !interrupts::are_enabled() {
+            return 0;
+        }
+        thread::yield_now();
+    }
+}
+
+/// The heap has no room for an allocation, even after running the shrinkers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError;
+
+/// Like `Box::new`, but fails instead of panicking.
+pub fn try_box<T>(value: T) -> Result<Box<T>, AllocError> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        return Ok(Box::new(value));
+    }
+    unsafe {
+        let ptr = alloc(layout) as *mut T;
+        if ptr.is_null() {
+            return Err(AllocError);
+        }
+        ptr.write(value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+/// Like `Vec::with_capacity`, but fails instead of panicking.
+pub fn try_vec_with_capacity<T>(capacity: usize) -> Result<Vec<T>, AllocError> {
+    let mut vec = Vec::new();
+    try_reserve(&mut vec, capacity)?;
+    Ok(vec)
+}
+
+/// Like `Vec::reserve`, but fails instead of panicking.
+pub fn try_reserve<T>(vec: &mut Vec<T>, additional: usize) -> Result<(), AllocError> {
+    vec.try_reserve(additional).map_err(|_| AllocError)
+}
+
+/// Like `Vec::push`, but hands `value` back if there is no memory for it.
+pub fn try_push<T>(vec: &mut Vec<T>, value: T) -> Result<(), T> {
+    match try_reserve(vec, 1) {
+        Ok(()) => {
+            vec.push(value);
+            Ok(())
+        }
+        Err(AllocError) => Err(value),
+    }
+}
@@ -0,0 +1,256 @@
+//! Heap usage per kernel task.
+//!
+//! While a task is polled it is the current one, and the global allocator
+//! charges every allocation to it. The allocator tags those allocations
+//! with their owner, so a free is credited to the task that allocated the
+//! memory, whichever task frees it. Memory handed to other tasks, like
+//! channel messages or task outputs, counts for its producer until it is
+//! freed. Allocations outside of tasks carry no tag.
+//!
+//! Interrupt handlers and user code interrupt whatever task runs, so they
+//! `suspend` charging while they run.
+
+use crate::ktask::TaskId;
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+const NO_LIMIT: usize = usize::MAX;
+
+/// The counters of one task, shared by the task and the registry.
+pub(crate) struct TaskMemory {
+    id: TaskId,
+    bytes: AtomicUsize,
+    objects: AtomicUsize,
+    allocations: AtomicU64,
+    failed: AtomicU64,
+    limit: AtomicUsize,
+}
+
+lazy_static! {
+    static ref TASKS: Mutex<BTreeMap<TaskId, Arc<TaskMemory>>> = Mutex::new(BTreeMap::new());
+}
+
+/// The task allocations are charged to, null outside of tasks.
+static CURRENT: AtomicPtr<TaskMemory> = AtomicPtr::new(ptr::null_mut());
+
+impl TaskMemory {
+    /// Starts accounting for the task `id`.
+    pub(crate) fn register(id: TaskId) -> Arc<TaskMemory> {
+        let memory = Arc::new(TaskMemory {
+            id,
+            bytes: AtomicUsize::new(0),
+            objects: AtomicUsize::new(0),
+            allocations: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            limit: AtomicUsize::new(NO_LIMIT),
+        });
+        TASKS.lock().insert(id, memory.clone());
+        memory
+    }
+
+    pub(crate) fn set_limit(&self, limit: Option<usize>) {
+        self.limit
+            .store(limit.unwrap_or(NO_LIMIT), Ordering::Relaxed);
+    }
+
+    fn usage(&self) -> MemoryUsage {
+        let limit = self.limit.load(Ordering::Relaxed);
+        MemoryUsage {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            objects: self.objects.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            limit: if limit == NO_LIMIT { None } else { Some(limit) },
+        }
+    }
+}
+
+/// Stops accounting for the task `id`, once it finished.
+pub(crate) fn unregister(id: TaskId) {
+    TASKS.lock().remove(&id);
+}
+
+/// Makes `memory` the current task until the guard is dropped.
+pub(crate) fn enter(memory: &Arc<TaskMemory>) -> Charging {
+    let task = Arc::as_ptr(memory) as *mut TaskMemory;
+    Charging {
+        previous: CURRENT.swap(task, Ordering::Relaxed),
+    }
+}
+
+/// Charges nothing to any task until the guard is dropped.
+pub(crate) fn suspend() -> Charging {
+    Charging {
+        previous: CURRENT.swap(ptr::null_mut(), Ordering::Relaxed),
+    }
+}
+
+/// Whether allocations are charged to a task right now.
+pub(crate) fn charging() -> bool {
+    !CURRENT.load(Ordering::Relaxed).is_null()
+}
+
+pub(crate) struct Charging {
+    previous: *mut TaskMemory,
+}
+
+impl Drop for Charging {
+    fn drop(&mut self) {
+        CURRENT.store(self.previous, Ordering::Relaxed);
+    }
+}
+
+/// The current task of a thread, saved while the thread is switched out.
+#[derive(Clone, Copy)]
+pub(crate) struct AccountingContext(*mut TaskMemory);
+
+// the pointer is never dereferenced, only put back into `CURRENT`
+unsafe impl Send for AccountingContext {}
+
+impl Default for AccountingContext {
+    fn default() -> Self {
+        AccountingContext(ptr::null_mut())
+    }
+}
+
+/// Called by `TaskContext::save` when a thread is switched out.
+pub(crate) fn save() -> AccountingContext {
+    AccountingContext(CURRENT.load(Ordering::Relaxed))
+}
+
+/// Called by `TaskContext::restore` when a thread is switched in.
+pub(crate) fn restore(context: AccountingContext) {
+    CURRENT.store(context.0, Ordering::Relaxed);
+}
+
+/// The task an allocation is charged to, stored with the allocation.
+///
+/// Every allocation keeps its task's counters alive, so memory a task
+/// leaves behind can still be credited after the task finished.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub(crate) struct Owner(*const TaskMemory);
+
+impl Owner {
+    /// Allocations made outside of tasks.
+    pub(crate) const NONE: Owner = Owner(ptr::null());
+}
+
+/// Charges an allocation of `size` bytes to the current task and returns
+/// it as the owner. Fails if that would exceed its limit, and the
+/// allocation must fail.
+///
+/// Called by the global allocator, so this must not allocate.
+pub(crate) fn charge(size: usize) -> Result<Owner, ()> {
+    let current = CURRENT.load(Ordering::Relaxed);
+    let task = match unsafe { current.as_ref() } {
+        Some(task) => task,
+        None => return Ok(Owner::NONE),
+    };
+    let bytes = task.bytes.fetch_add(size, Ordering::Relaxed) + size;
+    if bytes > task.limit.load(Ordering::Relaxed) {
+        task.bytes.fetch_sub(size, Ordering::Relaxed);
+        task.failed.fetch_add(1, Ordering::Relaxed);
+        return Err(());
+    }
+    task.objects.fetch_add(1, Ordering::Relaxed);
+    task.allocations.fetch_add(1, Ordering::Relaxed);
+    // `CURRENT` always points into a live `Arc`, see `enter`
+    unsafe { Arc::increment_strong_count(current) };
+    Ok(Owner(current))
+}
+
+/// Credits a free of `size` bytes, or a failed allocation, to `owner`.
+///
+/// May free the counters of a finished task, so the allocator must not be
+/// locked when calling this.
+///
+/// # Safety
+///
+/// `owner` must come from `charge`, and be credited only once.
+pub(crate) unsafe fn uncharge(owner: Owner, size: usize) {
+    if owner.0.is_null() {
+        return;
+    }
+    let task = Arc::from_raw(owner.0);
+    task.bytes.fetch_sub(size, Ordering::Relaxed);
+    task.objects.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Heap usage of a task.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryUsage {
+    /// Bytes in use, with the owner tags, rounded up to the allocator's
+    /// block sizes.
+    pub bytes: usize,
+    /// Allocations not freed yet.
+    pub objects: usize,
+    /// Allocations made so far.
+    pub allocations: u64,
+    /// Allocations refused because of the limit.
+    pub failed: u64,
+    pub limit: Option<usize>,
+}
+
+/// `None` if there is no task `id` (anymore).
+pub fn usage(id: TaskId) -> Option<MemoryUsage> {
+    TASKS.lock().get(&id).map(|task| task.usage())
+}
+
+/// Makes allocations of task `id` fail once it uses more than `limit`
+/// bytes. Returns false if there is no such task.
+pub fn set_limit(id: TaskId, limit: Option<usize>) -> bool {
+    match TASKS.lock().get(&id) {
+        Some(task) => {
+            task.set_limit(limit);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Every task with its heap usage, biggest first.
+pub fn report() -> MemoryReport {
+    let mut tasks: Vec<_> = TASKS
+        .lock()
+        .values()
+        .map(|task| (task.id, task.usage()))
+        .collect();
+    tasks.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes));
+    MemoryReport { tasks }
+}
+
+pub struct MemoryReport {
+    pub tasks: Vec<(TaskId, MemoryUsage)>,
+}
+
+impl fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>6} {:>10} {:>8} {:>10} {:>6} {:>10}",
+            "task", "bytes", "objects", "allocs", "failed", "limit"
+        )?;
+        for (id, usage) in self.tasks.iter() {
+            write!(
+                f,
+                "\n{:>6} {:>10} {:>8} {:>10} {:>6} ",
+                id.as_u64(),
+                usage.bytes,
+                usage.objects,
+                usage.allocations,
+                usage.failed
+            )?;
+            match usage.limit {
+                Some(limit) => write!(f, "{:>10}", limit)?,
+                None => write!(f, "{:>10}", "-")?,
+            }
+        }
+        Ok(())
+    }
+}
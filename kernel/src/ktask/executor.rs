@@ -1,25 +1,46 @@
-use crate::ktask::{KernelTask, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
-use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
-
-/// We assume that our kernel will have less than 128 kernel tasks
-/// currently. Maybe we need a flexible size in the future.
-const KERNEL_TASK_EXECUTOR_POOL_SIZE: usize = 128;
+use crate::ktask::{
+    self,
+    info::{TaskState, TaskStats},
+    spawner::{self, Spawner},
+    JoinHandle, KernelTask, Priority, Task, TaskId,
+};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    task::Wake,
+};
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
 
 pub struct Executor {
+    tasks: TaskSet,
+
+    /// Tasks spawned through a `Spawner` wait here until
+    /// `run_ready_tasks` moves them to `tasks`.
+    spawner: Spawner,
+
+    /// How many polls each priority gets per round, see `set_poll_budget`.
+    poll_budgets: [usize; Priority::COUNT],
+
+    /// Polls each priority had in the current round.
+    polls: [usize; Priority::COUNT],
+}
+
+/// The tasks of an executor, and their wakers.
+pub(super) struct TaskSet {
     /// Where all kernel tasks store
-    tasks: BTreeMap<TaskId, KernelTask>,
+    tasks: BTreeMap<TaskId, Task>,
 
-    /// We use this Arc<ArrayQueue> type for the task_queue
-    /// because it will be shared between the executor and wakers.
+    /// The ready queue is shared between the executor and wakers.
     /// The idea is that the wakers push the ID of the woken task to the queue.
     /// The executor sits on the receiving end of the queue,
     /// retrieves the woken tasks by their ID from the tasks map,
-    /// and then runs them. The reason for using a fixed-size queue
-    /// instead of an unbounded queue such as SegQueue is that
-    /// interrupt handlers that should not allocate will push to this queue.
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    /// and then runs them.
+    task_queue: Arc<ReadyQueue>,
 
     /// This map caches the Waker of a task after its creation.
     /// This has two reasons:
@@ -29,62 +50,173 @@ pub struct Executor {
     /// Second, it ensures that reference-counted wakers are
     /// not deallocated inside interrupt handlers
     /// because it could lead to deadlocks.
-    waker_cache: BTreeMap<TaskId, Waker>,
+    waker_cache: BTreeMap<TaskId, CachedWaker>,
+}
+
+/// Polls per round by default, by priority.
+const DEFAULT_POLL_BUDGETS: [usize; Priority::COUNT] = [32, 16, 4, 1];
+
+/// Ids of woken tasks, one queue per priority, each in the order the tasks
+/// were woken.
+///
+/// Interrupt handlers that must not allocate push to these queues, so each
+/// always has room for every task: a task is queued at most once, see
+/// `TaskWaker::queued`, its id leaves the queue together with the task,
+/// and the executor grows the queues whenever it takes in a new task.
+struct ReadyQueue {
+    /// Only locked with interrupts disabled, as wakers run in interrupt
+    /// handlers too.
+    ids: Mutex<[VecDeque<TaskId>; Priority::COUNT]>,
+}
+
+impl ReadyQueue {
+    fn push(&self, priority: Priority, task_id: TaskId) {
+        interrupts::without_interrupts(|| {
+            let mut queues = self.ids.lock();
+            let ids = &mut queues[priority.index()];
+            // growing here could allocate in an interrupt handler
+            if ids.len() == ids.capacity() {
+                panic!("ready queue out of room for task {}", task_id.as_u64());
+            }
+            ids.push_back(task_id);
+        });
+    }
+
+    /// Takes the next task of the highest priority `may_poll` allows.
+    /// Returns `None` if no allowed priority has ready tasks; `any_ready`
+    /// tells whether others have.
+    fn pop(&self, may_poll: impl Fn(Priority) -> bool) -> (Option<(Priority, TaskId)>, bool) {
+        interrupts::without_interrupts(|| {
+            let mut queues = self.ids.lock();
+            let mut any_ready = false;
+            for &priority in Priority::ALL.iter() {
+                let ids = &mut queues[priority.index()];
+                if ids.is_empty() {
+                    continue;
+                }
+                any_ready = true;
+                if may_poll(priority) {
+                    let task_id = ids.pop_front().expect("not empty");
+                    return (Some((priority, task_id)), true);
+                }
+            }
+            (None, any_ready)
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        interrupts::without_interrupts(|| self.ids.lock().iter().all(|ids| ids.is_empty()))
+    }
+
+    /// Takes `task_id` out of the queue, if it is in there.
+    fn remove(&self, priority: Priority, task_id: TaskId) {
+        interrupts::without_interrupts(|| {
+            self.ids.lock()[priority.index()].retain(|&id| id != task_id);
+        });
+    }
+
+    /// Makes room for `tasks` more ids than each queue holds now.
+    fn reserve(&self, tasks: usize) {
+        interrupts::without_interrupts(|| {
+            for ids in self.ids.lock().iter_mut() {
+                ids.reserve(tasks);
+            }
+        });
+    }
 }
 
 struct TaskWaker {
     /// The woken task id.
     task_id: TaskId,
 
+    /// The ready queue to push the task to.
+    priority: Priority,
+
+    /// Where the task is marked ready, see `info`.
+    stats: Arc<TaskStats>,
+
+    /// Whether the task is in the ready queue already, so waking it again
+    /// does nothing. The executor clears it right before polling the task,
+    /// and sets it for good once the task is gone.
+    queued: AtomicBool,
+
     /// the ownership of the task_queue is shared
     /// between the executor and wakers,
     /// we use the Arc wrapper type to implement
     /// shared reference-counted ownership.
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    task_queue: Arc<ReadyQueue>,
+}
+
+struct CachedWaker {
+    task_waker: Arc<TaskWaker>,
+    waker: Waker,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
-            tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(KERNEL_TASK_EXECUTOR_POOL_SIZE)),
-            waker_cache: BTreeMap::new(),
+            tasks: TaskSet::new(),
+            spawner: Spawner::new(),
+            poll_budgets: DEFAULT_POLL_BUDGETS,
+            polls: [0; Priority::COUNT],
         }
     }
 
-    pub fn spawn(&mut self, task: KernelTask) {
-        let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
-            panic!("task with same TID already exists");
-        }
-        self.task_queue.push(task_id).expect("kernel task full");
+    /// Sets how many tasks of `priority` are polled per round, at least
+    /// one. Ready tasks of higher priorities are polled first, until their
+    /// budgets are used up; a round ends once every priority with ready
+    /// tasks used up its budget. So a priority with ready tasks gets at
+    /// least its budget of polls in every round, however busy the others are.
+    pub fn set_poll_budget(&mut self, priority: Priority, polls: usize) {
+        assert!(polls > 0, "a poll budget of zero would starve the priority");
+        self.poll_budgets[priority.index()] = polls;
+    }
+
+    /// Queues `task` to be polled. Await the returned handle for its output,
+    /// or detach it if nobody cares.
+    pub fn spawn<T: 'static>(&mut self, task: KernelTask<T>) -> JoinHandle<T> {
+        self.spawner.spawn(task)
+    }
+
+    /// Cancels the task `id`, see `Spawner::abort`.
+    pub fn abort(&mut self, id: TaskId) {
+        self.spawner.abort(id);
+    }
+
+    /// A handle for spawning tasks onto this executor while it runs.
+    pub fn spawner(&self) -> Spawner {
+        self.spawner.clone()
     }
 
     fn run_ready_tasks(&mut self) {
         // destructure `self` to avoid borrow checker errors
         let Self {
             tasks,
-            task_queue,
-            waker_cache,
+            spawner,
+            poll_budgets,
+            polls,
         } = self;
+        let spawner = &*spawner;
+        let _entered = spawner::enter(spawner);
 
-        while let Ok(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
-            };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // task done -> remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+        loop {
+            // take in what was spawned since, e.g. by the task polled last
+            tasks.take_in(spawner);
+            let may_poll =
+                |priority: Priority| polls[priority.index()] < poll_budgets[priority.index()];
+            let task_id = match tasks.pop_ready(may_poll) {
+                (Some((priority, task_id)), _) => {
+                    polls[priority.index()] += 1;
+                    task_id
                 }
-                Poll::Pending => {}
-            }
+                // every priority with ready tasks used up its budget
+                (None, true) => {
+                    *polls = [0; Priority::COUNT];
+                    continue;
+                }
+                (None, false) => break,
+            };
+            tasks.poll(task_id);
         }
     }
 
@@ -96,40 +228,141 @@ impl Executor {
     }
 
     fn idle(&self) {
-        // there is still a subtle race condition in this implementation.
-        // Since interrupts are asynchronous and can happen at any time,
-        // it is possible that an interrupt happens right between
-        // the is_empty check and the call to hlt:
-        // if self.task_queue.is_empty() {
-        //     crate::cpu::hlt();
-        // }
-
-        use x86_64::instructions::interrupts;
-
-        // To avoid race conditions, we disable interrupts before checking
-        interrupts::disable();
-        if self.task_queue.is_empty() {
-            // enable interrupts and hlt the cpu until next task wakes.
-            interrupts::enable_interrupts_and_hlt();
-        } else {
-            // just re-enable interrupts.
-            interrupts::enable();
+        ktask::wait_for_work(|| ktask::Executor::has_ready_tasks(self));
+    }
+}
+
+impl ktask::Executor for Executor {
+    fn spawn<T: 'static>(&mut self, task: KernelTask<T>) -> JoinHandle<T> {
+        Executor::spawn(self, task)
+    }
+
+    fn spawner(&self) -> Spawner {
+        Executor::spawner(self)
+    }
+
+    fn run_until_idle(&mut self) {
+        self.run_ready_tasks();
+    }
+
+    fn has_ready_tasks(&self) -> bool {
+        !self.tasks.is_idle() || self.spawner.has_pending()
+    }
+}
+
+impl TaskSet {
+    pub(super) fn new() -> Self {
+        TaskSet {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ReadyQueue {
+                ids: Mutex::new([
+                    VecDeque::new(),
+                    VecDeque::new(),
+                    VecDeque::new(),
+                    VecDeque::new(),
+                ]),
+            }),
+            waker_cache: BTreeMap::new(),
         }
     }
+
+    /// Whether every task finished.
+    pub(super) fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Whether no task is ready to be polled.
+    pub(super) fn is_idle(&self) -> bool {
+        self.task_queue.is_empty()
+    }
+
+    /// Drops the tasks aborted through `spawner`, and takes in the ones
+    /// spawned through it, ready to be polled.
+    pub(super) fn take_in(&mut self, spawner: &Spawner) {
+        for task_id in spawner.take_aborted() {
+            // its `Joinable` wakes the joiners when dropped
+            self.remove(task_id);
+        }
+        let pending = spawner.take_pending();
+        if pending.is_empty() {
+            return;
+        }
+        // the room the wakers of the new tasks need
+        self.task_queue.reserve(self.tasks.len() + pending.len());
+        for task in pending {
+            let task_id = task.id;
+            let priority = task.priority;
+            let task_waker = TaskWaker::new(&task, self.task_queue.clone());
+            if self.tasks.insert(task_id, task).is_some() {
+                panic!("task with same TID already exists");
+            }
+            task_waker.queued.store(true, Ordering::Release);
+            self.task_queue.push(priority, task_id);
+            self.waker_cache.insert(
+                task_id,
+                CachedWaker {
+                    waker: Waker::from(task_waker.clone()),
+                    task_waker,
+                },
+            );
+        }
+    }
+
+    /// See `ReadyQueue::pop`.
+    pub(super) fn pop_ready(
+        &self,
+        may_poll: impl Fn(Priority) -> bool,
+    ) -> (Option<(Priority, TaskId)>, bool) {
+        self.task_queue.pop(may_poll)
+    }
+
+    /// Polls the task `task_id` once, and drops it if it finished.
+    pub(super) fn poll(&mut self, task_id: TaskId) {
+        let (task, cached) = match (self.tasks.get_mut(&task_id), self.waker_cache.get(&task_id)) {
+            (Some(task), Some(cached)) => (task, cached),
+            _ => return, // task no longer exists
+        };
+        // wakes from now on must poll the task again
+        cached.task_waker.queued.store(false, Ordering::Release);
+        let mut context = Context::from_waker(&cached.waker);
+        match task.poll(&mut context) {
+            Poll::Ready(()) => {
+                // task done -> remove it and its cached waker
+                self.remove(task_id);
+            }
+            Poll::Pending => {}
+        }
+    }
+
+    /// Drops a task, its cached waker and its id in the ready queue.
+    /// Wakers still held elsewhere keep the task marked as queued, so
+    /// waking them does nothing.
+    fn remove(&mut self, task_id: TaskId) {
+        if let Some(cached) = self.waker_cache.remove(&task_id) {
+            cached.task_waker.queued.store(true, Ordering::Release);
+            self.task_queue.remove(cached.task_waker.priority, task_id);
+        }
+        self.tasks.remove(&task_id);
+    }
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
+    fn new(task: &Task, task_queue: Arc<ReadyQueue>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
+            task_id: task.id,
+            priority: task.priority,
+            stats: task.stats.clone(),
+            queued: AtomicBool::new(false),
             task_queue,
-        }))
+        })
     }
 
     fn wake_task(&self) {
-        self.task_queue
-            .push(self.task_id)
-            .expect("kernel task full");
+        // only the first wake queues the task
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.stats.set_state(TaskState::Ready);
+            self.task_queue.push(self.priority, self.task_id);
+        }
     }
 }
 
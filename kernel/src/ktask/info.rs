@@ -0,0 +1,367 @@
+//! What the kernel tasks are doing, for diagnosing hung or busy tasks.
+//!
+//! Every spawned task registers its counters here, and the executor keeps
+//! them up to date. `tasks()` takes a snapshot that prints as a `ps`-style
+//! table. Finished tasks stay listed for a while, the most recent ones last.
+//!
+//! Every poll is timed. A task that doesn't return from `poll` holds up
+//! every other task of its executor, so the timer interrupt checks on the
+//! running poll and warns, with a backtrace, once it took longer than
+//! `set_poll_warning_threshold`.
+
+use crate::{
+    backtrace::Backtrace,
+    ktask::{Priority, TaskId},
+    println, time,
+};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, Ordering},
+    time::Duration,
+};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// How many finished tasks are kept around for listing.
+const FINISHED_HISTORY: usize = 16;
+
+/// Polls taking longer than this are reported, in nanoseconds.
+static POLL_WARNING_THRESHOLD: AtomicU64 = AtomicU64::new(100_000_000);
+
+/// The task being polled on this thread, if any.
+static POLLING: AtomicPtr<TaskStats> = AtomicPtr::new(ptr::null_mut());
+/// When the running poll started, in time stamp counter cycles.
+static POLL_START: AtomicU64 = AtomicU64::new(0);
+/// Whether the running poll was reported already, to warn only once.
+static POLL_REPORTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Woken and waiting to be polled.
+    Ready,
+    /// Waiting to be woken.
+    Pending,
+    /// Being polled right now.
+    Running,
+    /// Returned or cancelled.
+    Finished,
+}
+
+impl TaskState {
+    fn from_u8(state: u8) -> TaskState {
+        match state {
+            0 => TaskState::Ready,
+            1 => TaskState::Pending,
+            2 => TaskState::Running,
+            _ => TaskState::Finished,
+        }
+    }
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            TaskState::Ready => "ready",
+            TaskState::Pending => "pending",
+            TaskState::Running => "running",
+            TaskState::Finished => "finished",
+        })
+    }
+}
+
+/// The counters of one task, shared by the task, its waker and the registry.
+pub(crate) struct TaskStats {
+    id: TaskId,
+    name: String,
+    priority: Priority,
+    /// Ticks since boot when the task was spawned.
+    created: u64,
+    state: AtomicU8,
+    polls: AtomicU64,
+    /// Time stamp counter cycles spent in `poll`.
+    poll_cycles: AtomicU64,
+    /// The longest poll, in cycles.
+    max_poll_cycles: AtomicU64,
+}
+
+lazy_static! {
+    static ref TASKS: Mutex<BTreeMap<TaskId, Arc<TaskStats>>> = Mutex::new(BTreeMap::new());
+    static ref FINISHED: Mutex<VecDeque<Arc<TaskStats>>> = Mutex::new(VecDeque::new());
+}
+
+impl TaskStats {
+    /// Starts tracking the task `id`, which is ready to be polled.
+    pub(crate) fn register(id: TaskId, name: String, priority: Priority) -> Arc<TaskStats> {
+        let stats = Arc::new(TaskStats {
+            id,
+            name,
+            priority,
+            created: time::ticks(),
+            state: AtomicU8::new(TaskState::Ready as u8),
+            polls: AtomicU64::new(0),
+            poll_cycles: AtomicU64::new(0),
+            max_poll_cycles: AtomicU64::new(0),
+        });
+        TASKS.lock().insert(id, stats.clone());
+        stats
+    }
+
+    pub(crate) fn set_state(&self, state: TaskState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    /// Marks a task that was polled as pending, unless it was woken while
+    /// it ran.
+    pub(crate) fn set_pending(&self) {
+        let _ = self.state.compare_exchange(
+            TaskState::Running as u8,
+            TaskState::Pending as u8,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+    }
+
+    /// Counts a poll that took `cycles` of the time stamp counter.
+    fn record_poll(&self, cycles: u64) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.poll_cycles.fetch_add(cycles, Ordering::Relaxed);
+        self.max_poll_cycles.fetch_max(cycles, Ordering::Relaxed);
+    }
+
+    fn info(&self) -> TaskInfo {
+        TaskInfo {
+            id: self.id,
+            name: self.name.clone(),
+            priority: self.priority,
+            state: TaskState::from_u8(self.state.load(Ordering::Acquire)),
+            created: time::ticks_to_duration(self.created),
+            polls: self.polls.load(Ordering::Relaxed),
+            poll_time: time::cycles_to_duration(self.poll_cycles.load(Ordering::Relaxed)),
+            max_poll: time::cycles_to_duration(self.max_poll_cycles.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Times a poll of `task` until the guard is dropped, and lets the
+/// watchdog see it in the meantime.
+pub(crate) fn begin_poll(task: &TaskStats) -> Polling {
+    // an executor may be polled from inside a task, so polls nest. The
+    // outer poll keeps running meanwhile, so its clock isn't paused.
+    let previous = Polling {
+        task: POLLING.load(Ordering::Relaxed),
+        start: POLL_START.load(Ordering::Relaxed),
+        reported: POLL_REPORTED.load(Ordering::Relaxed),
+    };
+    set_poll(
+        task as *const TaskStats as *mut TaskStats,
+        time::cycles(),
+        false,
+    );
+    previous
+}
+
+/// Puts back the outer poll when dropped.
+pub(crate) struct Polling {
+    task: *mut TaskStats,
+    start: u64,
+    reported: bool,
+}
+
+impl Drop for Polling {
+    fn drop(&mut self) {
+        let task = POLLING.swap(ptr::null_mut(), Ordering::Relaxed);
+        // the guard outlives the borrow `begin_poll` got
+        if let Some(task) = unsafe { task.as_ref() } {
+            task.record_poll(time::cycles() - POLL_START.load(Ordering::Relaxed));
+        }
+        set_poll(self.task, self.start, self.reported);
+    }
+}
+
+fn set_poll(task: *mut TaskStats, start: u64, reported: bool) {
+    // hide the task from the watchdog until the start is in place
+    POLLING.store(ptr::null_mut(), Ordering::Relaxed);
+    POLL_START.store(start, Ordering::Relaxed);
+    POLL_REPORTED.store(reported, Ordering::Relaxed);
+    POLLING.store(task, Ordering::Relaxed);
+}
+
+/// The poll running on a thread, saved while the thread is switched out.
+/// The clock of the poll is paused meanwhile: being preempted doesn't
+/// block the executor, nor does it make the task slow.
+#[derive(Clone, Copy)]
+pub(crate) struct PollContext {
+    task: *mut TaskStats,
+    /// Cycles the poll ran before the thread was switched out.
+    elapsed: u64,
+    reported: bool,
+}
+
+// the task is only looked at again once it is back in `POLLING`
+unsafe impl Send for PollContext {}
+
+impl Default for PollContext {
+    fn default() -> Self {
+        PollContext {
+            task: ptr::null_mut(),
+            elapsed: 0,
+            reported: false,
+        }
+    }
+}
+
+/// Called by `TaskContext::save` when a thread is switched out.
+pub(crate) fn save() -> PollContext {
+    PollContext {
+        task: POLLING.load(Ordering::Relaxed),
+        elapsed: time::cycles().wrapping_sub(POLL_START.load(Ordering::Relaxed)),
+        reported: POLL_REPORTED.load(Ordering::Relaxed),
+    }
+}
+
+/// Called by `TaskContext::restore` when a thread is switched in.
+pub(crate) fn restore(context: PollContext) {
+    let start = time::cycles().wrapping_sub(context.elapsed);
+    set_poll(context.task, start, context.reported);
+}
+
+/// Sets how long a poll may take before it is reported.
+pub fn set_poll_warning_threshold(threshold: Duration) {
+    POLL_WARNING_THRESHOLD.store(threshold.as_nanos() as u64, Ordering::Relaxed);
+}
+
+pub fn poll_warning_threshold() -> Duration {
+    Duration::from_nanos(POLL_WARNING_THRESHOLD.load(Ordering::Relaxed))
+}
+
+/// Called from the timer interrupt. Warns about the running poll once it
+/// takes too long, with a backtrace of where it is stuck.
+pub(crate) fn watchdog_tick() {
+    // the poll can't end while we interrupt it, so the task stays alive
+    let task = match unsafe { POLLING.load(Ordering::Relaxed).as_ref() } {
+        Some(task) => task,
+        None => return,
+    };
+    if POLL_REPORTED.load(Ordering::Relaxed) {
+        return;
+    }
+    let elapsed = time::cycles_to_duration(time::cycles() - POLL_START.load(Ordering::Relaxed));
+    if elapsed < poll_warning_threshold() {
+        return;
+    }
+    POLL_REPORTED.store(true, Ordering::Relaxed);
+    println!(
+        "[ktask] warning: task {} ({}) polled for {}ms, blocking its executor{}",
+        task.id.as_u64(),
+        task.name,
+        elapsed.as_millis(),
+        Backtrace::here()
+    );
+}
+
+/// Stops tracking the task `id` once it is dropped, keeping it in the
+/// history of finished tasks.
+pub(crate) fn unregister(id: TaskId) {
+    let stats = match TASKS.lock().remove(&id) {
+        Some(stats) => stats,
+        None => return,
+    };
+    stats.set_state(TaskState::Finished);
+    let evicted = {
+        let mut finished = FINISHED.lock();
+        finished.push_back(stats);
+        if finished.len() > FINISHED_HISTORY {
+            finished.pop_front()
+        } else {
+            None
+        }
+    };
+    drop(evicted);
+}
+
+/// A task at the time of the snapshot.
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: String,
+    pub priority: Priority,
+    pub state: TaskState,
+    /// Uptime when the task was spawned.
+    pub created: Duration,
+    pub polls: u64,
+    /// Time spent polling the task, in total.
+    pub poll_time: Duration,
+    /// The longest poll.
+    pub max_poll: Duration,
+}
+
+impl TaskInfo {
+    pub fn average_poll(&self) -> Duration {
+        if self.polls == 0 {
+            return Duration::from_secs(0);
+        }
+        let nanos = self.poll_time.as_nanos() / self.polls as u128;
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
+/// `None` if there is no task `id`, or it finished a while ago.
+pub fn task(id: TaskId) -> Option<TaskInfo> {
+    if let Some(stats) = TASKS.lock().get(&id) {
+        return Some(stats.info());
+    }
+    FINISHED
+        .lock()
+        .iter()
+        .find(|stats| stats.id == id)
+        .map(|stats| stats.info())
+}
+
+/// Every task, by id, followed by the recently finished ones.
+pub fn tasks() -> TaskList {
+    let mut tasks: Vec<_> = TASKS.lock().values().map(|stats| stats.info()).collect();
+    tasks.extend(FINISHED.lock().iter().map(|stats| stats.info()));
+    TaskList { tasks }
+}
+
+pub struct TaskList {
+    pub tasks: Vec<TaskInfo>,
+}
+
+impl TaskList {
+    pub fn iter(&self) -> impl Iterator<Item = &TaskInfo> {
+        self.tasks.iter()
+    }
+}
+
+impl fmt::Display for TaskList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>6} {:<16} {:<11} {:<8} {:>10} {:>8} {:>10} {:>8} {:>10}",
+            "task", "name", "priority", "state", "started", "polls", "time", "avg", "max"
+        )?;
+        for task in self.tasks.iter() {
+            write!(
+                f,
+                "\n{:>6} {:<16} {:<11} {:<8} {:>8}ms {:>8} {:>8}us {:>6}us {:>8}us",
+                task.id.as_u64(),
+                task.name,
+                alloc::format!("{:?}", task.priority),
+                task.state,
+                task.created.as_millis(),
+                task.polls,
+                task.poll_time.as_micros(),
+                task.average_poll().as_micros(),
+                task.max_poll.as_micros()
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+//! Waiting for kernel tasks to finish, and cancelling them.
+//!
+//! Every task's future is wrapped in a `Joinable`, which hands the output
+//! to the task's `JoinHandle`. If the task is dropped before it finished,
+//! whether aborted or because its executor went away, the `Joinable` tells
+//! the handle it was cancelled instead.
+
+use crate::ktask::TaskId;
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+/// Why a task has no output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was aborted, or dropped with its executor.
+    Cancelled,
+}
+
+/// What a task and its handle share.
+pub(crate) struct JoinState<T> {
+    output: Option<Result<T, JoinError>>,
+    finished: bool,
+    /// Nobody is going to take the output, so it is dropped right away.
+    detached: bool,
+    /// Set by `JoinHandle::abort`, the task stops the next time it is polled.
+    abort_requested: bool,
+    /// The task awaiting the handle.
+    waker: Option<Waker>,
+    /// The waker of the task itself, to get it polled after an abort.
+    task_waker: Option<Waker>,
+}
+
+impl<T> JoinState<T> {
+    pub(crate) fn new() -> Arc<Mutex<JoinState<T>>> {
+        Arc::new(Mutex::new(JoinState {
+            output: None,
+            finished: false,
+            detached: false,
+            abort_requested: false,
+            waker: None,
+            task_waker: None,
+        }))
+    }
+}
+
+/// Hands `output` to the handle and wakes whoever awaits it.
+fn complete<T>(state: &Mutex<JoinState<T>>, output: Result<T, JoinError>) {
+    let mut guard = state.lock();
+    guard.finished = true;
+    let task_waker = guard.task_waker.take();
+    if guard.detached {
+        drop(guard);
+        drop(output);
+        drop(task_waker);
+        return;
+    }
+    guard.output = Some(output);
+    let waker = guard.waker.take();
+    drop(guard);
+    drop(task_waker);
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// The future of a task, reporting to its `JoinHandle`.
+pub(crate) struct Joinable<F: Future> {
+    /// `None` once the future returned or was cancelled.
+    future: Option<F>,
+    state: Arc<Mutex<JoinState<F::Output>>>,
+}
+
+impl<F: Future> Joinable<F> {
+    pub(crate) fn new(future: F, state: Arc<Mutex<JoinState<F::Output>>>) -> Self {
+        Joinable {
+            future: Some(future),
+            state,
+        }
+    }
+
+    /// Drops the future, running its destructors, and reports the task as
+    /// cancelled.
+    fn cancel(&mut self) {
+        if self.future.take().is_some() {
+            complete(&self.state, Err(JoinError::Cancelled));
+        }
+    }
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        // the future is never moved: it stays in place until it is dropped
+        let this = unsafe { self.get_unchecked_mut() };
+        {
+            let mut state = this.state.lock();
+            if state.abort_requested {
+                drop(state);
+                this.cancel();
+                return Poll::Ready(());
+            }
+            match state.task_waker {
+                Some(ref waker) if waker.will_wake(context.waker()) => {}
+                _ => state.task_waker = Some(context.waker().clone()),
+            }
+        }
+
+        let future = match this.future.as_mut() {
+            Some(future) => unsafe { Pin::new_unchecked(future) },
+            None => return Poll::Ready(()),
+        };
+        match future.poll(context) {
+            Poll::Ready(output) => {
+                this.future = None;
+                complete(&this.state, Ok(output));
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<F: Future> Drop for Joinable<F> {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Owned permission to await a task's output. Dropping it detaches the task.
+pub struct JoinHandle<T> {
+    id: TaskId,
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub(crate) fn new(id: TaskId, state: Arc<Mutex<JoinState<T>>>) -> Self {
+        JoinHandle { id, state }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Whether the task returned or was cancelled, i.e. awaiting the handle
+    /// won't have to wait.
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().finished
+    }
+
+    /// Cancels the task: its future is dropped the next time its executor
+    /// gets to it, instead of being polled, and the handle resolves to
+    /// `JoinError::Cancelled`. Does nothing if the task finished already.
+    pub fn abort(&self) {
+        let mut state = self.state.lock();
+        if state.finished {
+            return;
+        }
+        state.abort_requested = true;
+        let task_waker = state.task_waker.take();
+        drop(state);
+        // a task that was never polled is still queued anyway
+        if let Some(waker) = task_waker {
+            waker.wake();
+        }
+    }
+
+    /// Lets the task run on without anyone waiting for it. Its output is
+    /// dropped when it finishes.
+    pub fn detach(self) {}
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        if state.finished {
+            let output = state
+                .output
+                .take()
+                .expect("JoinHandle polled after completion");
+            return Poll::Ready(output);
+        }
+        match state.waker {
+            Some(ref waker) if waker.will_wake(context.waker()) => {}
+            _ => state.waker = Some(context.waker().clone()),
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        state.detached = true;
+        let output = state.output.take();
+        let waker = state.waker.take();
+        drop(state);
+        // dropped outside of the lock, they may run arbitrary code
+        drop(output);
+        drop(waker);
+    }
+}
@@ -1,39 +1,288 @@
-use alloc::boxed::Box;
+use accounting::{AccountingContext, TaskMemory};
+use alloc::{boxed::Box, format, string::String, sync::Arc, task::Wake};
 use core::{
+    fmt,
     future::Future,
     pin::Pin,
-    sync::atomic::{AtomicU64, Ordering},
-    task::{Context, Poll},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
 };
+use info::{PollContext, TaskState, TaskStats};
+use join::{JoinState, Joinable};
+use simple_executor::SimpleExecutor;
+use spawner::SpawnerContext;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
 
+pub mod accounting;
 pub mod executor;
+pub mod info;
+pub mod join;
 pub mod kernel_tasks;
 pub mod simple_executor;
+pub mod spawner;
+pub mod sync;
+
+pub use join::{JoinError, JoinHandle};
+pub use spawner::{abort, spawn, Spawner};
+
+/// Everything the task machinery keeps about the code running on a thread:
+/// the task charged for allocations, the executor `spawn` uses and the poll
+/// the watchdog times. Opaque to the scheduler, which saves it when it
+/// switches a thread out and restores it when it switches it back in.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct TaskContext {
+    accounting: AccountingContext,
+    spawner: SpawnerContext,
+    polling: PollContext,
+}
+
+impl TaskContext {
+    /// Called by the scheduler when a thread is switched out.
+    pub(crate) fn save() -> Self {
+        TaskContext {
+            accounting: accounting::save(),
+            spawner: spawner::save(),
+            polling: info::save(),
+        }
+    }
+
+    /// Called by the scheduler when a thread is switched in.
+    pub(crate) fn restore(self) {
+        accounting::restore(self.accounting);
+        spawner::restore(self.spawner);
+        info::restore(self.polling);
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Scheduling class of a task. The executor polls ready tasks of a higher
+/// priority first, but every class gets its poll budget per round, see
+/// `Executor::set_poll_budget`, so lower ones can't starve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Deferred work of interrupt handlers, e.g. decoding scancodes.
+    BottomHalf,
+    /// Tasks a user is waiting on, like the keyboard handling.
+    Interactive,
+    Normal,
+    /// Work nobody is waiting on, like flushing disks or shipping logs.
+    Background,
+}
+
+impl Priority {
+    pub const COUNT: usize = 4;
+
+    /// From highest to lowest.
+    pub const ALL: [Priority; Priority::COUNT] = [
+        Priority::BottomHalf,
+        Priority::Interactive,
+        Priority::Normal,
+        Priority::Background,
+    ];
+
+    pub(crate) fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
 }
 
-pub struct KernelTask {
+/// A future to run on an executor, whose output `T` can be awaited through
+/// the `JoinHandle` that spawning it returns.
+pub struct KernelTask<T = ()> {
     id: TaskId,
+    name: Option<String>,
+    priority: Priority,
+    memory_limit: Option<usize>,
     future: Pin<Box<dyn Future<Output = ()>>>,
+    join: Arc<Mutex<JoinState<T>>>,
 }
 
-impl KernelTask {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> KernelTask {
+impl<T: 'static> KernelTask<T> {
+    pub fn new(future: impl Future<Output = T> + 'static) -> KernelTask<T> {
+        let join = JoinState::new();
         KernelTask {
             id: TaskId::new(),
-            future: Box::pin(future),
+            name: None,
+            priority: Priority::default(),
+            memory_limit: None,
+            future: Box::pin(Joinable::new(future, join.clone())),
+            join,
         }
     }
 
+    /// Names the task in listings, see `info::tasks`.
+    pub fn with_name(mut self, name: impl Into<String>) -> KernelTask<T> {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> KernelTask<T> {
+        self.priority = priority;
+        self
+    }
+
+    /// Makes allocations of the task fail once it uses more than `limit`
+    /// bytes of heap.
+    pub fn with_memory_limit(mut self, limit: usize) -> KernelTask<T> {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Splits the task into what executors poll and the handle to its output.
+    pub(crate) fn into_parts(self) -> (Task, JoinHandle<T>) {
+        let id = self.id;
+        let memory = TaskMemory::register(id);
+        memory.set_limit(self.memory_limit);
+        let name = self.name.unwrap_or_else(|| format!("task {}", id));
+        let task = Task {
+            id,
+            future: self.future,
+            memory,
+            stats: TaskStats::register(id, name, self.priority),
+            priority: self.priority,
+        };
+        (task, JoinHandle::new(id, self.join))
+    }
+}
+
+/// A spawned task, with its output type erased.
+pub(crate) struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+    /// What the task allocated, see `accounting`.
+    memory: Arc<TaskMemory>,
+    /// What the task did so far, see `info`.
+    stats: Arc<TaskStats>,
+    priority: Priority,
+}
+
+impl Task {
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        let _charging = accounting::enter(&self.memory);
+        self.stats.set_state(TaskState::Running);
+        let poll = {
+            let _polling = info::begin_poll(&self.stats);
+            self.future.as_mut().poll(context)
+        };
+        match poll {
+            Poll::Ready(()) => self.stats.set_state(TaskState::Finished),
+            Poll::Pending => self.stats.set_pending(),
+        }
+        poll
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        accounting::unregister(self.id);
+        info::unregister(self.id);
+    }
+}
+
+/// What the executors have in common, so code can drive futures without
+/// caring which executor runs them.
+pub trait Executor {
+    /// Queues `task` to be polled.
+    fn spawn<T: 'static>(&mut self, task: KernelTask<T>) -> JoinHandle<T>;
+
+    /// A handle for spawning tasks onto this executor while it runs.
+    fn spawner(&self) -> Spawner;
+
+    /// Polls tasks until none is ready, taking in the ones spawned in the
+    /// meantime. Tasks waiting to be woken are left alone.
+    fn run_until_idle(&mut self);
+
+    /// Whether `run_until_idle` has something to do. Called with
+    /// interrupts disabled, right before halting the CPU.
+    fn has_ready_tasks(&self) -> bool;
+
+    /// Runs the tasks until `future` completes, and returns its output.
+    /// The future runs on the current stack, so it may borrow locals, and
+    /// may spawn tasks onto this executor with `ktask::spawn`.
+    fn block_on<F: Future>(&mut self, future: F) -> F::Output {
+        // polled first without being woken
+        let woken = Arc::new(BlockOnWaker(AtomicBool::new(true)));
+        let waker = Waker::from(woken.clone());
+        let mut context = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        let spawner = self.spawner();
+        loop {
+            if woken.0.swap(false, Ordering::AcqRel) {
+                let _entered = spawner::enter(&spawner);
+                if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                    return output;
+                }
+            }
+            self.run_until_idle();
+            wait_for_work(|| woken.0.load(Ordering::Acquire) || self.has_ready_tasks());
+        }
+    }
+}
+
+/// Wakes `Executor::block_on`, which polls its future outside any task.
+struct BlockOnWaker(AtomicBool);
+
+impl Wake for BlockOnWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+/// Runs `future` to completion on a fresh `SimpleExecutor`, with the tasks
+/// it spawns. Meant for code that runs before the main executor, like
+/// early boot.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    SimpleExecutor::new().block_on(future)
+}
+
+/// Halts the CPU until the next interrupt, unless `has_work` says there is
+/// something to do already.
+fn wait_for_work(has_work: impl FnOnce() -> bool) {
+    // An interrupt could wake a task right between checking for work and
+    // halting, and we would sleep with a task ready. So check with
+    // interrupts disabled, and enable them and halt in one go.
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    if has_work() {
+        if enabled {
+            interrupts::enable();
+        }
+        return;
     }
+    // nothing could ever wake us
+    assert!(enabled, "waiting for tasks with interrupts disabled");
+    interrupts::enable_interrupts_and_hlt();
 }
@@ -1,44 +1,73 @@
-use crate::ktask::KernelTask;
-use alloc::collections::VecDeque;
-use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use crate::ktask::{
+    self,
+    executor::TaskSet,
+    spawner::{self, Spawner},
+    JoinHandle, KernelTask, TaskId,
+};
 
+/// Polls tasks strictly by priority, and in the order they were woken
+/// within a priority. Unlike `Executor` it has no poll budgets and doesn't
+/// run forever, so the same tasks and wakes always run in the same order.
+/// Meant for early boot and for trying out tasks.
 pub struct SimpleExecutor {
-    tasks: VecDeque<KernelTask>,
+    tasks: TaskSet,
+    spawner: Spawner,
 }
 
 impl SimpleExecutor {
     pub fn new() -> SimpleExecutor {
         SimpleExecutor {
-            tasks: VecDeque::new(),
+            tasks: TaskSet::new(),
+            spawner: Spawner::new(),
         }
     }
 
-    pub fn spawn(&mut self, task: KernelTask) {
-        self.tasks.push_back(task)
+    pub fn spawn<T: 'static>(&mut self, task: KernelTask<T>) -> JoinHandle<T> {
+        self.spawner.spawn(task)
     }
 
+    /// Cancels the task `id`, see `Spawner::abort`.
+    pub fn abort(&mut self, id: TaskId) {
+        self.spawner.abort(id);
+    }
+
+    pub fn spawner(&self) -> Spawner {
+        self.spawner.clone()
+    }
+
+    /// Runs until every task finished, halting while all of them wait.
     pub fn run(&mut self) {
-        while let Some(mut task) = self.tasks.pop_front() {
-            let waker = no_op_waker();
-            let mut context = Context::from_waker(&waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {} // task done
-                Poll::Pending => self.tasks.push_back(task),
+        loop {
+            ktask::Executor::run_until_idle(self);
+            if self.tasks.is_empty() {
+                break;
             }
+            ktask::wait_for_work(|| ktask::Executor::has_ready_tasks(self));
         }
     }
 }
 
-fn no_op_waker_raw() -> RawWaker {
-    fn no_op(_: *const ()) {}
-    fn clone(_: *const ()) -> RawWaker {
-        no_op_waker_raw()
+impl ktask::Executor for SimpleExecutor {
+    fn spawn<T: 'static>(&mut self, task: KernelTask<T>) -> JoinHandle<T> {
+        SimpleExecutor::spawn(self, task)
     }
 
-    let vtable = &RawWakerVTable::new(clone, no_op, no_op, no_op);
-    RawWaker::new(0 as *const (), vtable)
-}
+    fn spawner(&self) -> Spawner {
+        SimpleExecutor::spawner(self)
+    }
 
-fn no_op_waker() -> Waker {
-    unsafe { Waker::from_raw(no_op_waker_raw()) }
+    fn run_until_idle(&mut self) {
+        let _entered = spawner::enter(&self.spawner);
+        loop {
+            self.tasks.take_in(&self.spawner);
+            match self.tasks.pop_ready(|_| true) {
+                (Some((_, task_id)), _) => self.tasks.poll(task_id),
+                _ => break,
+            }
+        }
+    }
+
+    fn has_ready_tasks(&self) -> bool {
+        !self.tasks.is_idle() || self.spawner.has_pending()
+    }
 }
@@ -0,0 +1,134 @@
+//! Spawning tasks from inside other tasks.
+//!
+//! Tasks spawned through a `Spawner` wait in a queue shared with the
+//! executor, which moves them over to its own tasks before polling. The
+//! futures of tasks needn't be `Send`, so neither is a `Spawner`: it can only
+//! be used on the thread running its executor.
+
+use crate::ktask::{JoinHandle, KernelTask, Task, TaskId};
+use alloc::{collections::VecDeque, rc::Rc, vec::Vec};
+use core::{
+    cell::RefCell,
+    future::Future,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+/// A handle to spawn tasks onto an executor, see `Executor::spawner`.
+#[derive(Clone)]
+pub struct Spawner {
+    pending: Rc<RefCell<VecDeque<Task>>>,
+    /// Tasks to drop before polling any others.
+    aborted: Rc<RefCell<Vec<TaskId>>>,
+}
+
+impl Spawner {
+    pub(crate) fn new() -> Self {
+        Spawner {
+            pending: Rc::new(RefCell::new(VecDeque::new())),
+            aborted: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    /// Queues `task` on the executor. It is polled the next time the
+    /// executor looks for ready tasks.
+    pub fn spawn<T: 'static>(&self, task: KernelTask<T>) -> JoinHandle<T> {
+        let (task, handle) = task.into_parts();
+        self.pending.borrow_mut().push_back(task);
+        handle
+    }
+
+    /// Cancels the task `id` of the executor, like `JoinHandle::abort`. The
+    /// executor drops it before polling any other task, and its handle
+    /// resolves to `JoinError::Cancelled`. Ids of finished tasks are ignored.
+    pub fn abort(&self, id: TaskId) {
+        self.aborted.borrow_mut().push(id);
+    }
+
+    /// Takes the tasks spawned since the last call.
+    pub(crate) fn take_pending(&self) -> VecDeque<Task> {
+        self.pending.replace(VecDeque::new())
+    }
+
+    /// Takes the tasks aborted since the last call.
+    pub(crate) fn take_aborted(&self) -> Vec<TaskId> {
+        self.aborted.replace(Vec::new())
+    }
+
+    pub(crate) fn has_pending(&self) -> bool {
+        !self.pending.borrow().is_empty() || !self.aborted.borrow().is_empty()
+    }
+}
+
+/// The spawner of the executor running on the current thread, null if none.
+static CURRENT: AtomicPtr<Spawner> = AtomicPtr::new(ptr::null_mut());
+
+/// Makes `spawner` the current one until the guard is dropped.
+pub(crate) fn enter(spawner: &Spawner) -> Entered {
+    let spawner = spawner as *const Spawner as *mut Spawner;
+    Entered {
+        previous: CURRENT.swap(spawner, Ordering::Relaxed),
+    }
+}
+
+pub(crate) struct Entered {
+    previous: *mut Spawner,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.store(self.previous, Ordering::Relaxed);
+    }
+}
+
+/// The current spawner of a thread, saved while the thread is switched out.
+#[derive(Clone, Copy)]
+pub(crate) struct SpawnerContext(*mut Spawner);
+
+// the pointer is only dereferenced on the thread it was saved from
+unsafe impl Send for SpawnerContext {}
+
+impl Default for SpawnerContext {
+    fn default() -> Self {
+        SpawnerContext(ptr::null_mut())
+    }
+}
+
+/// Called by `TaskContext::save` when a thread is switched out.
+pub(crate) fn save() -> SpawnerContext {
+    SpawnerContext(CURRENT.load(Ordering::Relaxed))
+}
+
+/// Called by `TaskContext::restore` when a thread is switched in.
+pub(crate) fn restore(context: SpawnerContext) {
+    CURRENT.store(context.0, Ordering::Relaxed);
+}
+
+/// The spawner of the executor running the current task, `None` outside
+/// of tasks.
+pub fn current() -> Option<Spawner> {
+    // the executor keeps its spawner alive while the pointer is set
+    unsafe { CURRENT.load(Ordering::Relaxed).as_ref() }.cloned()
+}
+
+/// Spawns `future` onto the executor running the current task.
+///
+/// # Panics
+///
+/// When called outside of a task.
+pub fn spawn<T: 'static>(future: impl Future<Output = T> + 'static) -> JoinHandle<T> {
+    current()
+        .expect("ktask::spawn called outside of a task")
+        .spawn(KernelTask::new(future))
+}
+
+/// Aborts the task `id` of the executor running the current task.
+///
+/// # Panics
+///
+/// When called outside of a task.
+pub fn abort(id: TaskId) {
+    current()
+        .expect("ktask::abort called outside of a task")
+        .abort(id)
+}
@@ -0,0 +1,114 @@
+use crate::ktask::sync::{IrqMutex, Waiters};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+struct State {
+    /// Tasks waiting in the current round.
+    arrived: usize,
+    /// Counts completed rounds.
+    generation: u64,
+    waiters: Waiters,
+}
+
+/// Lets `n` tasks wait for each other. Once the last one arrives, all of
+/// them continue and the barrier can be used again.
+pub struct Barrier {
+    tasks: usize,
+    state: IrqMutex<State>,
+}
+
+/// What `Barrier::wait` returns.
+#[derive(Debug, Clone, Copy)]
+pub struct BarrierWaitResult {
+    leader: bool,
+}
+
+impl BarrierWaitResult {
+    /// True for exactly one task per round, the one that arrived last.
+    pub fn is_leader(&self) -> bool {
+        self.leader
+    }
+}
+
+impl Barrier {
+    pub fn new(tasks: usize) -> Self {
+        assert!(tasks > 0, "a barrier needs a task");
+        Barrier {
+            tasks,
+            state: IrqMutex::new(State {
+                arrived: 0,
+                generation: 0,
+                waiters: Waiters::new(),
+            }),
+        }
+    }
+
+    pub fn wait(&self) -> BarrierWait<'_> {
+        BarrierWait {
+            barrier: self,
+            waiter: None,
+            generation: None,
+        }
+    }
+}
+
+/// Future of `Barrier::wait`. Dropping it before the round completes
+/// takes the task out of the round again.
+pub struct BarrierWait<'a> {
+    barrier: &'a Barrier,
+    waiter: Option<u64>,
+    /// The round we arrived in.
+    generation: Option<u64>,
+}
+
+impl Future for BarrierWait<'_> {
+    type Output = BarrierWaitResult;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<BarrierWaitResult> {
+        let this = &mut *self;
+        let tasks = this.barrier.tasks;
+        let waiter = &mut this.waiter;
+        let generation = &mut this.generation;
+        this.barrier.state.lock(|state| match *generation {
+            None => {
+                state.arrived += 1;
+                if state.arrived == tasks {
+                    state.arrived = 0;
+                    state.generation += 1;
+                    state.waiters.wake_all();
+                    *generation = Some(state.generation);
+                    return Poll::Ready(BarrierWaitResult { leader: true });
+                }
+                *generation = Some(state.generation);
+                state.waiters.register(waiter, context.waker());
+                Poll::Pending
+            }
+            Some(round) if round != state.generation => {
+                *waiter = None;
+                Poll::Ready(BarrierWaitResult { leader: false })
+            }
+            Some(_) => {
+                state.waiters.register(waiter, context.waker());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl Drop for BarrierWait<'_> {
+    fn drop(&mut self) {
+        let generation = self.generation;
+        let waiter = self.waiter;
+        self.barrier.state.lock(|state| {
+            if generation == Some(state.generation) && waiter.is_some() {
+                state.arrived -= 1;
+                if let Some(id) = waiter {
+                    state.waiters.remove(id);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,226 @@
+//! Channels delivering every value to every receiver, e.g. key events to
+//! whoever listens.
+//!
+//! Values go into a ring buffer allocated with the channel. A receiver that
+//! falls behind by more than its capacity misses the oldest values and is
+//! told how many it lagged. `Sender::send` never allocates and never waits,
+//! so it may be called from interrupt handlers, though it drops the value
+//! it overwrites there.
+
+use crate::ktask::sync::{IrqMutex, Waiters};
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// There are no receivers; the value comes back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// Every sender is gone and everything sent was received.
+    Closed,
+    /// This many values were overwritten before the receiver got to them.
+    /// It continues with the oldest value left.
+    Lagged(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// Nothing new was sent.
+    Empty,
+    Closed,
+    Lagged(u64),
+}
+
+struct State<T> {
+    /// Value number `n` is at index `n % capacity`.
+    buffer: Vec<Option<T>>,
+    /// Number of the next value sent.
+    next: u64,
+    senders: usize,
+    receivers: usize,
+    waiting: Waiters,
+}
+
+struct Shared<T> {
+    state: IrqMutex<State<T>>,
+}
+
+/// A channel keeping the last `capacity` values, at least one.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "a channel needs room for a value");
+    let shared = Arc::new(Shared {
+        state: IrqMutex::new(State {
+            buffer: (0..capacity).map(|_| None).collect(),
+            next: 0,
+            senders: 1,
+            receivers: 1,
+            waiting: Waiters::new(),
+        }),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver {
+            shared,
+            next: 0,
+            waiter: None,
+        },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// Sends `value` to every receiver there is now. Returns how many.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let result = self.shared.state.lock(|state| {
+            if state.receivers == 0 {
+                return Err(SendError(value));
+            }
+            let index = (state.next % state.buffer.len() as u64) as usize;
+            let old = state.buffer[index].replace(value);
+            state.next += 1;
+            state.waiting.wake_all();
+            Ok((state.receivers, old))
+        });
+        result.map(|(receivers, _old)| receivers)
+    }
+
+    /// A receiver getting the values sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let next = self.shared.state.lock(|state| {
+            state.receivers += 1;
+            state.next
+        });
+        Receiver {
+            shared: self.shared.clone(),
+            next,
+            waiter: None,
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.state.lock(|state| state.receivers)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock(|state| state.senders += 1);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock(|state| {
+            state.senders -= 1;
+            if state.senders == 0 {
+                // receivers see the channel closed
+                state.waiting.wake_all();
+            }
+        });
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    /// Number of the next value to receive.
+    next: u64,
+    waiter: Option<u64>,
+}
+
+impl<T: Clone> Receiver<T> {
+    /// The next value, waiting for one if there is none.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let next = &mut self.next;
+        self.shared.state.lock(|state| Self::take(state, next))
+    }
+
+    fn take(state: &mut State<T>, next: &mut u64) -> Result<T, TryRecvError> {
+        let capacity = state.buffer.len() as u64;
+        let oldest = state.next.saturating_sub(capacity);
+        if *next < oldest {
+            let lagged = oldest - *next;
+            *next = oldest;
+            return Err(TryRecvError::Lagged(lagged));
+        }
+        if *next == state.next {
+            return Err(if state.senders == 0 {
+                TryRecvError::Closed
+            } else {
+                TryRecvError::Empty
+            });
+        }
+        let value = state.buffer[(*next % capacity) as usize]
+            .clone()
+            .expect("sent values stay until overwritten");
+        *next += 1;
+        Ok(value)
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    /// A receiver at the same position, getting the same values from now on.
+    fn clone(&self) -> Self {
+        self.shared.state.lock(|state| state.receivers += 1);
+        Receiver {
+            shared: self.shared.clone(),
+            next: self.next,
+            waiter: None,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let waiter = self.waiter;
+        self.shared.state.lock(|state| {
+            state.receivers -= 1;
+            if let Some(id) = waiter {
+                state.waiting.remove(id);
+            }
+        });
+    }
+}
+
+/// Future of `Receiver::recv`.
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T: Clone> Future for Recv<'_, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let receiver = &mut *self.receiver;
+        let next = &mut receiver.next;
+        let waiter = &mut receiver.waiter;
+        receiver.shared.state.lock(|state| {
+            match Receiver::take(state, next) {
+                Ok(value) => Poll::Ready(Ok(value)),
+                Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError::Closed)),
+                Err(TryRecvError::Lagged(lagged)) => Poll::Ready(Err(RecvError::Lagged(lagged))),
+                Err(TryRecvError::Empty) => {
+                    // woken by the next send, which drains the waiters
+                    state.waiting.register(waiter, context.waker());
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,139 @@
+//! Communication and synchronization between kernel tasks.
+//!
+//! Channels pass values between tasks; the locks and events here park the
+//! waiting tasks instead of spinning, so they may be held across an
+//! `.await`, and serve waiters in the order they came.
+//!
+//! Everything here wakes waiting tasks through their wakers, so it works
+//! with any executor. The shared state sits behind locks that are only taken
+//! with interrupts disabled, so the sending ends that don't allocate may be
+//! used from interrupt handlers too. They don't free either: wakers are
+//! woken by reference and dropped later by the waiting side, as dropping
+//! the last waker of a task frees it.
+
+use alloc::{collections::VecDeque, vec::Vec};
+use core::task::Waker;
+use spin::Mutex as SpinMutex;
+use x86_64::instructions::interrupts;
+
+pub mod broadcast;
+pub mod mpsc;
+pub mod oneshot;
+
+mod barrier;
+mod mutex;
+mod notify;
+mod rwlock;
+mod semaphore;
+
+pub use barrier::{Barrier, BarrierWait, BarrierWaitResult};
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::{Event, EventWait, Notified, Notify};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::{Acquire, Semaphore, SemaphorePermit};
+
+/// A spin lock that is only ever taken with interrupts disabled, so an
+/// interrupt handler can't find it held by the code it interrupted.
+pub(crate) struct IrqMutex<T> {
+    inner: SpinMutex<T>,
+}
+
+impl<T> IrqMutex<T> {
+    pub(crate) const fn new(value: T) -> Self {
+        IrqMutex {
+            inner: SpinMutex::new(value),
+        }
+    }
+
+    pub(crate) fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.inner.lock()))
+    }
+}
+
+/// Tasks waiting for something, in the order they started waiting.
+///
+/// A future registers itself with the id in its `slot`, and must `remove`
+/// itself when dropped. If it was notified already by then, it should pass
+/// the notification on, as nobody else will be.
+pub(crate) struct Waiters {
+    queue: VecDeque<(u64, Waker)>,
+    /// Wakers `wake_all` used up. Dropping a waker may free its task, which
+    /// interrupt handlers must not, so they are dropped by the next
+    /// `register` or `remove`. Always has room for the whole `queue`.
+    woken: Vec<Waker>,
+    next_id: u64,
+}
+
+impl Waiters {
+    pub(crate) fn new() -> Self {
+        Waiters {
+            queue: VecDeque::new(),
+            woken: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Adds the waiter in `slot`, or updates its waker if it is waiting
+    /// already. Allocates, so not for interrupt handlers.
+    pub(crate) fn register(&mut self, slot: &mut Option<u64>, waker: &Waker) {
+        self.woken.clear();
+        if let Some(id) = *slot {
+            if let Some(entry) = self.queue.iter_mut().find(|entry| entry.0 == id) {
+                if !entry.1.will_wake(waker) {
+                    entry.1 = waker.clone();
+                }
+                return;
+            }
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queue.push_back((id, waker.clone()));
+        self.woken.reserve(self.queue.len());
+        *slot = Some(id);
+    }
+
+    /// Removes the waiter `id`. Returns false if it was notified already.
+    pub(crate) fn remove(&mut self, id: u64) -> bool {
+        self.woken.clear();
+        match self.queue.iter().position(|entry| entry.0 == id) {
+            Some(index) => {
+                self.queue.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the waiter `id` is still waiting, i.e. wasn't notified.
+    pub(crate) fn contains(&self, id: u64) -> bool {
+        self.queue.iter().any(|entry| entry.0 == id)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Wakes the longest waiting waiter and returns its id, `None` if
+    /// nobody waits. Like `wake_all`, neither allocates nor frees.
+    pub(crate) fn wake_one(&mut self) -> Option<u64> {
+        let (id, waker) = self.queue.pop_front()?;
+        waker.wake_by_ref();
+        self.woken.push(waker);
+        Some(id)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Wakes every waiter. Neither allocates nor frees, so it may run in
+    /// interrupt handlers: the wakers of the executors only queue the task,
+    /// and the wakers are kept in `woken` instead of being dropped.
+    pub(crate) fn wake_all(&mut self) {
+        for (_, waker) in self.queue.drain(..) {
+            waker.wake_by_ref();
+            // `register` made room for this
+            self.woken.push(waker);
+        }
+    }
+}
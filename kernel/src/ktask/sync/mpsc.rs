@@ -0,0 +1,377 @@
+//! Channels with any number of senders and a single receiver.
+//!
+//! A bounded channel allocates its buffer up front, so `Sender::try_send`
+//! never allocates and may be called from interrupt handlers, e.g. to hand
+//! scancodes to a task. Unbounded channels allocate on send.
+
+use crate::ktask::sync::{IrqMutex, Waiters};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use futures_util::stream::Stream;
+
+/// The receiver is gone; the value comes back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel has no room right now.
+    Full(T),
+    /// The receiver is gone.
+    Closed(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// Nothing was sent yet.
+    Empty,
+    /// Every sender is gone and everything sent was received.
+    Closed,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    /// `None` for unbounded channels.
+    capacity: Option<usize>,
+    senders: usize,
+    receiver_alive: bool,
+    receiver: Option<Waker>,
+    /// Senders waiting for room.
+    senders_waiting: Waiters,
+    /// Room promised to senders that were woken but didn't send yet, so
+    /// that senders coming later can't take it.
+    reserved: usize,
+}
+
+impl<T> State<T> {
+    /// Whether a sender that didn't wait yet may send right away.
+    fn has_free_room(&self) -> bool {
+        match self.capacity {
+            Some(capacity) => {
+                self.senders_waiting.is_empty() && self.queue.len() + self.reserved < capacity
+            }
+            None => true,
+        }
+    }
+
+    /// Wakes the longest waiting sender if there is room for it.
+    fn notify_sender(&mut self) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        if self.queue.len() + self.reserved >= capacity {
+            return;
+        }
+        if self.senders_waiting.wake_one().is_some() {
+            self.reserved += 1;
+        }
+    }
+
+    /// Wakes the receiver, keeping its waker: the receiver drops it when it
+    /// replaces it, so sending never frees.
+    fn wake_receiver(&self) {
+        if let Some(waker) = &self.receiver {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+struct Shared<T> {
+    state: IrqMutex<State<T>>,
+}
+
+impl<T> Shared<T> {
+    fn new(capacity: Option<usize>) -> Arc<Self> {
+        Arc::new(Shared {
+            state: IrqMutex::new(State {
+                queue: VecDeque::with_capacity(capacity.unwrap_or(0)),
+                capacity,
+                senders: 1,
+                receiver_alive: true,
+                receiver: None,
+                senders_waiting: Waiters::new(),
+                reserved: 0,
+            }),
+        })
+    }
+
+    /// Queues `value` if there is room nobody waits for, and wakes the
+    /// receiver.
+    fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.state.lock(|state| {
+            if !state.receiver_alive {
+                return Err(TrySendError::Closed(value));
+            }
+            if !state.has_free_room() {
+                return Err(TrySendError::Full(value));
+            }
+            state.queue.push_back(value);
+            state.wake_receiver();
+            Ok(())
+        })
+    }
+
+    fn add_sender(&self) {
+        self.state.lock(|state| state.senders += 1);
+    }
+
+    fn drop_sender(&self) {
+        self.state.lock(|state| {
+            state.senders -= 1;
+            // the receiver sees the channel closed
+            if state.senders == 0 {
+                state.wake_receiver();
+            }
+        });
+    }
+}
+
+/// A channel holding at most `capacity` values, at least one.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "a channel needs room for a value");
+    let shared = Shared::new(Some(capacity));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// A channel whose senders never wait, but allocate.
+pub fn unbounded<T>() -> (UnboundedSender<T>, Receiver<T>) {
+    let shared = Shared::new(None);
+    (
+        UnboundedSender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// The sending end of a bounded channel.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, waiting for room if the channel is full. Senders get
+    /// room in the order they started waiting.
+    pub fn send(&self, value: T) -> Send<'_, T> {
+        Send {
+            sender: self,
+            value: Some(value),
+            waiter: None,
+        }
+    }
+
+    /// Sends `value` if there is room right now that no waiting sender is
+    /// owed. Doesn't allocate, so it may be called from interrupt handlers.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.shared.try_send(value)
+    }
+
+    /// Whether the receiver is gone.
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock(|state| !state.receiver_alive)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.add_sender();
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.drop_sender();
+    }
+}
+
+/// Future of `Sender::send`.
+pub struct Send<'a, T> {
+    sender: &'a Sender<T>,
+    value: Option<T>,
+    waiter: Option<u64>,
+}
+
+// the value is moved out, never pinned
+impl<T> Unpin for Send<'_, T> {}
+
+impl<T> Future for Send<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let value = this.value.take().expect("Send polled after completion");
+        let waiter = &mut this.waiter;
+        let result = this.sender.shared.state.lock(|state| {
+            if !state.receiver_alive {
+                return Err(TrySendError::Closed(value));
+            }
+            let send = match *waiter {
+                // woken, with room reserved for us
+                Some(id) if !state.senders_waiting.contains(id) => {
+                    state.reserved -= 1;
+                    true
+                }
+                Some(_) => false,
+                None => state.has_free_room(),
+            };
+            if send {
+                *waiter = None;
+                state.queue.push_back(value);
+                state.wake_receiver();
+                return Ok(());
+            }
+            state.senders_waiting.register(waiter, context.waker());
+            Err(TrySendError::Full(value))
+        });
+        match result {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(TrySendError::Closed(value)) => Poll::Ready(Err(SendError(value))),
+            Err(TrySendError::Full(value)) => {
+                this.value = Some(value);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for Send<'_, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.waiter.take() {
+            // pass on the room we were woken for
+            self.sender.shared.state.lock(|state| {
+                if !state.senders_waiting.remove(id) && state.receiver_alive {
+                    state.reserved -= 1;
+                    state.notify_sender();
+                }
+            });
+        }
+    }
+}
+
+/// The sending end of an unbounded channel.
+pub struct UnboundedSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> UnboundedSender<T> {
+    /// Queues `value`. Allocates, so not for interrupt handlers.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.shared.try_send(value).map_err(|error| match error {
+            TrySendError::Full(value) | TrySendError::Closed(value) => SendError(value),
+        })
+    }
+
+    /// Whether the receiver is gone.
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock(|state| !state.receiver_alive)
+    }
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.add_sender();
+        UnboundedSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for UnboundedSender<T> {
+    fn drop(&mut self) {
+        self.shared.drop_sender();
+    }
+}
+
+/// The receiving end of a channel. Also a `Stream` of the values.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// The next value, or `None` once every sender is gone and everything
+    /// sent was received.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.shared
+            .state
+            .lock(|state| match state.queue.pop_front() {
+                Some(value) => {
+                    // there is room for a waiting sender now
+                    state.notify_sender();
+                    Ok(value)
+                }
+                None if state.senders == 0 => Err(TryRecvError::Closed),
+                None => Err(TryRecvError::Empty),
+            })
+    }
+
+    fn poll_recv(&mut self, context: &mut Context) -> Poll<Option<T>> {
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Some(value)),
+            Err(TryRecvError::Closed) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+        let waker = context.waker().clone();
+        let old = self
+            .shared
+            .state
+            .lock(|state| state.receiver.replace(waker));
+        drop(old);
+        // a value may have come in before the waker was in place
+        match self.try_recv() {
+            Ok(value) => Poll::Ready(Some(value)),
+            Err(TryRecvError::Closed) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let (queue, _receiver) = self.shared.state.lock(|state| {
+            state.receiver_alive = false;
+            // waiting senders find the channel closed
+            state.senders_waiting.wake_all();
+            (core::mem::take(&mut state.queue), state.receiver.take())
+        });
+        // values are dropped outside of the lock, they may run arbitrary code
+        drop(queue);
+    }
+}
+
+/// Future of `Receiver::recv`.
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<T>> {
+        self.receiver.poll_recv(context)
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(context)
+    }
+}
@@ -0,0 +1,220 @@
+use crate::ktask::sync::{IrqMutex, Waiters};
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+struct NotifyState {
+    waiters: Waiters,
+    /// A `notify_one` nobody was waiting for, taken by the next waiter.
+    permit: bool,
+    /// Waiters woken by `notify_one` that didn't see it yet. One that goes
+    /// away instead passes its notification on. Always has room for every
+    /// waiter, so notifying never allocates.
+    woken_by_one: Vec<u64>,
+}
+
+impl NotifyState {
+    fn notify_one(&mut self) {
+        match self.waiters.wake_one() {
+            // `Notified::poll` made room for this
+            Some(id) => self.woken_by_one.push(id),
+            None => self.permit = true,
+        }
+    }
+
+    /// Whether `id` was woken by `notify_one`, forgetting it.
+    fn take_woken_by_one(&mut self, id: u64) -> bool {
+        match self.woken_by_one.iter().position(|&woken| woken == id) {
+            Some(index) => {
+                self.woken_by_one.swap_remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Wakes tasks waiting for something to happen, like a condition variable
+/// without the lock. The notifying side doesn't allocate, so interrupt
+/// handlers may notify.
+pub struct Notify {
+    state: IrqMutex<NotifyState>,
+}
+
+impl Notify {
+    pub fn new() -> Self {
+        Notify {
+            state: IrqMutex::new(NotifyState {
+                waiters: Waiters::new(),
+                permit: false,
+                woken_by_one: Vec::new(),
+            }),
+        }
+    }
+
+    /// Waits for a notification.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            waiter: None,
+        }
+    }
+
+    /// Wakes the task that waited longest. If none waits, the next call to
+    /// `notified` completes right away.
+    pub fn notify_one(&self) {
+        self.state.lock(|state| state.notify_one());
+    }
+
+    /// Wakes every task waiting right now.
+    pub fn notify_all(&self) {
+        self.state.lock(|state| state.waiters.wake_all());
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify::new()
+    }
+}
+
+/// Future of `Notify::notified`.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    waiter: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        let this = &mut *self;
+        let waiter = &mut this.waiter;
+        this.notify.state.lock(|state| match *waiter {
+            None if state.permit => {
+                state.permit = false;
+                Poll::Ready(())
+            }
+            Some(id) if !state.waiters.contains(id) => {
+                state.take_woken_by_one(id);
+                *waiter = None;
+                Poll::Ready(())
+            }
+            _ => {
+                state.waiters.register(waiter, context.waker());
+                let waiting = state.waiters.len();
+                state.woken_by_one.reserve(waiting);
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let id = match self.waiter {
+            Some(id) => id,
+            None => return,
+        };
+        self.notify.state.lock(|state| {
+            if state.waiters.remove(id) {
+                return;
+            }
+            // woken by `notify_one` but gone, so pass it on
+            if state.take_woken_by_one(id) {
+                state.notify_one();
+            }
+        });
+    }
+}
+
+struct EventState {
+    set: bool,
+    waiters: Waiters,
+}
+
+/// A flag tasks can wait for. Stays set until reset, so every task waiting
+/// now or later gets through. Setting doesn't allocate, so interrupt
+/// handlers may set it.
+pub struct Event {
+    state: IrqMutex<EventState>,
+}
+
+impl Event {
+    pub fn new() -> Self {
+        Event {
+            state: IrqMutex::new(EventState {
+                set: false,
+                waiters: Waiters::new(),
+            }),
+        }
+    }
+
+    pub fn set(&self) {
+        self.state.lock(|state| {
+            state.set = true;
+            state.waiters.wake_all();
+        });
+    }
+
+    pub fn reset(&self) {
+        self.state.lock(|state| state.set = false);
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.state.lock(|state| state.set)
+    }
+
+    /// Waits until the event is set.
+    pub fn wait(&self) -> EventWait<'_> {
+        EventWait {
+            event: self,
+            waiter: None,
+        }
+    }
+}
+
+impl Default for Event {
+    fn default() -> Self {
+        Event::new()
+    }
+}
+
+/// Future of `Event::wait`.
+pub struct EventWait<'a> {
+    event: &'a Event,
+    waiter: Option<u64>,
+}
+
+impl Future for EventWait<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        let this = &mut *self;
+        let waiter = &mut this.waiter;
+        this.event.state.lock(|state| {
+            // woken by `set`, even if it was reset since
+            let woken = waiter.map_or(false, |id| !state.waiters.contains(id));
+            if state.set || woken {
+                if let Some(id) = waiter.take() {
+                    state.waiters.remove(id);
+                }
+                Poll::Ready(())
+            } else {
+                state.waiters.register(waiter, context.waker());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl Drop for EventWait<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.waiter {
+            self.event.state.lock(|state| state.waiters.remove(id));
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Channels for a single value, e.g. the reply to a request.
+//!
+//! The value has its slot allocated with the channel, so `Sender::send`
+//! never allocates and may be called from interrupt handlers. It frees
+//! nothing either, unless the receiver is gone and the channel with it.
+
+use crate::ktask::sync::IrqMutex;
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// The sender was dropped without sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// Nothing was sent yet.
+    Empty,
+    /// The sender was dropped without sending, or the value was received.
+    Closed,
+}
+
+struct State<T> {
+    value: Option<T>,
+    sender_alive: bool,
+    receiver_alive: bool,
+    receiver: Option<Waker>,
+}
+
+impl<T> State<T> {
+    /// Wakes the receiver, keeping its waker: the receiver drops it, so
+    /// the sender never frees.
+    fn wake_receiver(&self) {
+        if let Some(waker) = &self.receiver {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+struct Shared<T> {
+    state: IrqMutex<State<T>>,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: IrqMutex::new(State {
+            value: None,
+            sender_alive: true,
+            receiver_alive: true,
+            receiver: None,
+        }),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Hands `value` to the receiver, or back if the receiver is gone.
+    pub fn send(self, value: T) -> Result<(), T> {
+        self.shared.state.lock(|state| {
+            if !state.receiver_alive {
+                return Err(value);
+            }
+            state.value = Some(value);
+            state.wake_receiver();
+            Ok(())
+        })
+    }
+
+    /// Whether the receiver is gone, so sending is pointless.
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock(|state| !state.receiver_alive)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock(|state| {
+            state.sender_alive = false;
+            state.wake_receiver();
+        });
+    }
+}
+
+/// A future resolving to the value sent.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.shared.state.lock(|state| match state.value.take() {
+            Some(value) => Ok(value),
+            None if state.sender_alive => Err(TryRecvError::Empty),
+            None => Err(TryRecvError::Closed),
+        })
+    }
+
+    /// Makes the sender see the channel closed. A value sent already can
+    /// still be received.
+    pub fn close(&mut self) {
+        self.shared.state.lock(|state| state.receiver_alive = false);
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let (result, old) = self.shared.state.lock(|state| {
+            if let Some(value) = state.value.take() {
+                return (Poll::Ready(Ok(value)), None);
+            }
+            if !state.sender_alive {
+                return (Poll::Ready(Err(RecvError)), None);
+            }
+            (
+                Poll::Pending,
+                state.receiver.replace(context.waker().clone()),
+            )
+        });
+        drop(old);
+        result
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let (value, _receiver) = self.shared.state.lock(|state| {
+            state.receiver_alive = false;
+            (state.value.take(), state.receiver.take())
+        });
+        // dropped outside of the lock, it may run arbitrary code
+        drop(value);
+    }
+}
@@ -0,0 +1,209 @@
+use crate::ktask::sync::IrqMutex;
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+struct Waiter {
+    id: u64,
+    permits: usize,
+    waker: Waker,
+}
+
+struct State {
+    permits: usize,
+    /// In the order they started waiting. Permits are handed to the first
+    /// one as soon as there are enough for it, so a task asking for many
+    /// permits can't be overtaken by ones asking for few.
+    waiters: VecDeque<Waiter>,
+    /// Wakers `grant` used up, dropped by the next waiter that comes or
+    /// goes: dropping a waker may free its task. Always has room for every
+    /// waiter.
+    woken: Vec<Waker>,
+    next_id: u64,
+}
+
+impl State {
+    /// Hands out permits to waiters from the front, as far as they go.
+    /// Neither allocates nor frees, so releasing may happen in interrupt
+    /// handlers.
+    fn grant(&mut self) {
+        while let Some(waiter) = self.waiters.front() {
+            if waiter.permits > self.permits {
+                break;
+            }
+            self.permits -= waiter.permits;
+            let waiter = self.waiters.pop_front().expect("not empty");
+            waiter.waker.wake_by_ref();
+            self.woken.push(waiter.waker);
+        }
+    }
+}
+
+/// A fair, async counting semaphore.
+pub struct Semaphore {
+    state: IrqMutex<State>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            state: IrqMutex::new(State {
+                permits,
+                waiters: VecDeque::new(),
+                woken: Vec::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock(|state| state.permits)
+    }
+
+    /// Adds `permits`, e.g. from an interrupt handler when a device frees
+    /// a slot.
+    pub fn add_permits(&self, permits: usize) {
+        self.state.lock(|state| {
+            state.permits += permits;
+            state.grant();
+        });
+    }
+
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Waits until `permits` are available, after every task that started
+    /// waiting earlier got its permits.
+    pub fn acquire_many(&self, permits: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            permits,
+            waiter: None,
+        }
+    }
+
+    /// Takes `permits` if they are available and nobody waits for some.
+    pub fn try_acquire_many(&self, permits: usize) -> Option<SemaphorePermit<'_>> {
+        self.state.lock(|state| {
+            if state.waiters.is_empty() && state.permits >= permits {
+                state.permits -= permits;
+                Some(SemaphorePermit {
+                    semaphore: self,
+                    permits,
+                })
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+}
+
+/// Permits taken from a semaphore, given back on drop.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permits from being given back.
+    pub fn forget(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(self.permits);
+    }
+}
+
+/// Future of `Semaphore::acquire_many`.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+    /// Our id among the waiters, once we wait.
+    waiter: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<SemaphorePermit<'a>> {
+        let this = &mut *self;
+        let permits = this.permits;
+        let waiter = &mut this.waiter;
+        let acquired = this.semaphore.state.lock(|state| {
+            state.woken.clear();
+            match *waiter {
+                None if state.waiters.is_empty() && state.permits >= permits => {
+                    state.permits -= permits;
+                    return true;
+                }
+                None => {
+                    let id = state.next_id;
+                    state.next_id += 1;
+                    state.waiters.push_back(Waiter {
+                        id,
+                        permits,
+                        waker: context.waker().clone(),
+                    });
+                    let waiting = state.waiters.len();
+                    state.woken.reserve(waiting);
+                    *waiter = Some(id);
+                }
+                Some(id) => match state.waiters.iter_mut().find(|waiter| waiter.id == id) {
+                    Some(waiter) => {
+                        if !waiter.waker.will_wake(context.waker()) {
+                            waiter.waker = context.waker().clone();
+                        }
+                    }
+                    // `grant` took our permits out already
+                    None => {
+                        *waiter = None;
+                        return true;
+                    }
+                },
+            }
+            false
+        });
+        if acquired {
+            Poll::Ready(SemaphorePermit {
+                semaphore: this.semaphore,
+                permits,
+            })
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let id = match self.waiter {
+            Some(id) => id,
+            None => return,
+        };
+        let permits = self.permits;
+        self.semaphore.state.lock(|state| {
+            state.woken.clear();
+            match state.waiters.iter().position(|waiter| waiter.id == id) {
+                Some(index) => {
+                    state.waiters.remove(index);
+                }
+                // granted but never taken, give them back
+                None => state.permits += permits,
+            }
+            // the ones behind us may fit now
+            state.grant();
+        });
+    }
+}
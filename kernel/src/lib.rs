@@ -1,18 +1,24 @@
 #![no_std]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
+#![feature(asm)]
 #![feature(const_fn)]
 #![feature(const_in_array_repeat_expressions)]
+#![feature(global_asm)]
+#![feature(try_reserve)]
 #![feature(wake_trait)]
 
 extern crate alloc;
 
-use crate::memory::BootInfoFrameAllocator;
 use bootloader::BootInfo;
 use x86_64::VirtAddr;
 
 pub mod allocators;
+pub mod backtrace;
 pub mod cpu;
+pub mod dma;
+pub mod elf;
+pub mod fpu;
 /// In 64-bit mode, the GDT is mostly used for two things:
 /// Switching between kernel space and user space,
 /// and loading a TSS structure.
@@ -20,19 +26,38 @@ pub mod gdt;
 pub mod idt;
 pub mod kalloc;
 pub mod ktask;
+pub mod loader;
 pub mod memory;
+pub mod mmio;
 pub mod panic;
+pub mod process;
+pub mod programs;
+pub mod syscall;
+pub mod thread;
+pub mod time;
+pub mod usermode;
 pub mod vga;
 
 pub fn init(boot: &'static BootInfo) {
     gdt::init_gdt();
+    // faults while setting up memory get reported, on the boot IST stacks
     idt::init_idt();
+
+    memory::init(VirtAddr::new(boot.physical_memory_offset), &boot.memory_map);
+    memory::protection::init();
+    dma::init();
+
+    kalloc::init_kernel_heap().expect("heap initialization failed");
+    vga::init();
+
+    // exceptions get guarded stacks before interrupts are enabled
+    memory::stack::register_current_stack("the boot stack");
+    gdt::init_interrupt_stacks();
+
     idt::init_pics();
+    time::init();
+    fpu::init();
     idt::enable_interrupts();
 
-    let mut page_table = memory::init(VirtAddr::new(boot.physical_memory_offset));
-    let mut frame_allocator = BootInfoFrameAllocator::init(&boot.memory_map);
-
-    kalloc::init_kernel_heap(&mut page_table, &mut frame_allocator)
-        .expect("heap initialization failed");
+    thread::init();
 }
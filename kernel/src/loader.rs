@@ -0,0 +1,203 @@
+//! Loads ELF executables into a fresh address space and runs them in ring 3.
+
+use crate::{
+    elf::{ElfError, ElfFile, PF_W, PF_X},
+    memory::{
+        address_space::{is_user_range, USER_SPACE_END},
+        AddressSpace,
+    },
+};
+use alloc::vec::Vec;
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+
+/// The initial user stack sits right below the end of user space,
+/// with an unmapped page above it.
+pub const USER_STACK_TOP: u64 = USER_SPACE_END - 4096;
+pub const USER_STACK_SIZE: u64 = 64 * 1024;
+
+// auxiliary vector entry types, as defined by the System V ABI
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    Elf(ElfError),
+    /// A segment is not inside user space.
+    BadSegment(u64),
+    /// argv and envp don't fit on the initial stack.
+    ArgumentsTooLarge,
+    OutOfMemory,
+}
+
+impl From<ElfError> for LoadError {
+    fn from(error: ElfError) -> Self {
+        LoadError::Elf(error)
+    }
+}
+
+/// A program loaded into its own address space, ready to be entered.
+pub struct UserImage {
+    pub address_space: AddressSpace,
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+}
+
+/// Maps every `PT_LOAD` segment of `image` into a fresh address space and
+/// builds the initial stack with `argv`, `envp` and the auxiliary vector.
+pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<UserImage, LoadError> {
+    let elf = ElfFile::parse(image)?;
+    let mut address_space = AddressSpace::new().ok_or(LoadError::OutOfMemory)?;
+
+    for segment in elf.load_segments() {
+        let start =
+            VirtAddr::try_new(segment.vaddr).map_err(|_| LoadError::BadSegment(segment.vaddr))?;
+        if segment.memsz == 0 {
+            continue;
+        }
+        if !is_user_range(start, segment.memsz) {
+            return Err(LoadError::BadSegment(segment.vaddr));
+        }
+
+        let mut flags = PageTableFlags::empty();
+        if segment.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if segment.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+
+        let first = Page::containing_address(start);
+        let last = Page::containing_address(start + (segment.memsz - 1));
+        for page in Page::range_inclusive(first, last) {
+            address_space
+                .map_user_page(page, flags)
+                .map_err(|_| LoadError::OutOfMemory)?;
+        }
+
+        // frames start out zeroed, but be explicit about BSS in case the
+        // page is shared with another segment.
+        let file_end = start + segment.filesz;
+        address_space
+            .write(start, &elf.data()[segment.file_range()])
+            .and_then(|_| address_space.zero(file_end, (segment.memsz - segment.filesz) as usize))
+            .expect("segment pages were just mapped");
+    }
+
+    let entry =
+        VirtAddr::try_new(elf.header.entry).map_err(|_| LoadError::BadSegment(elf.header.entry))?;
+    let stack_pointer = setup_stack(&mut address_space, &elf, argv, envp)?;
+    Ok(UserImage {
+        address_space,
+        entry,
+        stack_pointer,
+    })
+}
+
+/// Lays out the initial stack as expected by the System V ABI:
+///
+/// ```text
+/// USER_STACK_TOP -> argv and envp strings, AT_RANDOM bytes
+///                   auxv pairs, terminated by AT_NULL
+///                   envp pointers, terminated by NULL
+///                   argv pointers, terminated by NULL
+/// stack_pointer  -> argc
+/// ```
+fn setup_stack(
+    address_space: &mut AddressSpace,
+    elf: &ElfFile,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<VirtAddr, LoadError> {
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    let first = Page::containing_address(VirtAddr::new(stack_bottom));
+    let last = Page::containing_address(VirtAddr::new(USER_STACK_TOP - 1));
+    // most of the stack is never touched, so only pages written to get a
+    // frame of their own
+    for page in Page::range_inclusive(first, last) {
+        address_space
+            .map_zero_page(page, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+            .map_err(|_| LoadError::OutOfMemory)?;
+    }
+
+    let mut sp = USER_STACK_TOP;
+    let mut push = |bytes: &[u8], sp: &mut u64| -> Result<u64, LoadError> {
+        if *sp - stack_bottom < bytes.len() as u64 {
+            return Err(LoadError::ArgumentsTooLarge);
+        }
+        *sp -= bytes.len() as u64;
+        address_space
+            .write(VirtAddr::new(*sp), bytes)
+            .expect("stack pages were just mapped");
+        Ok(*sp)
+    };
+
+    let mut push_strings = |strings: &[&str], sp: &mut u64| -> Result<Vec<u64>, LoadError> {
+        let mut pointers = Vec::with_capacity(strings.len());
+        for s in strings {
+            push(&[0], sp)?;
+            pointers.push(push(s.as_bytes(), sp)?);
+        }
+        Ok(pointers)
+    };
+    let argv_pointers = push_strings(argv, &mut sp)?;
+    let envp_pointers = push_strings(envp, &mut sp)?;
+    let random = push(&random_bytes(), &mut sp)?;
+
+    let mut words = Vec::new();
+    words.push(argv_pointers.len() as u64);
+    words.extend_from_slice(&argv_pointers);
+    words.push(0);
+    words.extend_from_slice(&envp_pointers);
+    words.push(0);
+    if let Some(phdr) = elf.program_headers_vaddr() {
+        words.extend_from_slice(&[AT_PHDR, phdr]);
+    }
+    words.extend_from_slice(&[
+        AT_PHENT,
+        elf.header.phentsize as u64,
+        AT_PHNUM,
+        elf.header.phnum as u64,
+        AT_PAGESZ,
+        4096,
+        AT_ENTRY,
+        elf.header.entry,
+        AT_RANDOM,
+        random,
+        AT_NULL,
+        0,
+    ]);
+
+    // argc must end up 16-byte aligned
+    sp &= !0xf;
+    if words.len() % 2 == 1 {
+        words.push(0);
+    }
+    let bytes: Vec<u8> = words
+        .iter()
+        .flat_map(|word| word.to_le_bytes().to_vec())
+        .collect();
+    push(&bytes, &mut sp)?;
+    Ok(VirtAddr::new(sp))
+}
+
+/// Seed for the user's stack protector and hash tables. Not cryptographic,
+/// but different on every run.
+fn random_bytes() -> [u8; 16] {
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&tsc.to_le_bytes());
+    bytes[8..].copy_from_slice(
+        &tsc.rotate_left(29)
+            .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+            .to_le_bytes(),
+    );
+    bytes
+}
@@ -1,55 +0,0 @@
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
-use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
-    PhysAddr, VirtAddr,
-};
-
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
-}
-
-impl BootInfoFrameAllocator {
-    pub fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
-    }
-
-    fn unused_4kib_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        self.memory_map
-            .iter()
-            .filter(|r| r.region_type == MemoryRegionType::Usable)
-            // map each region to its address range
-            .map(|r| r.range.start_addr()..r.range.end_addr())
-            // transform to an iterator of frame start addresses
-            .flat_map(|r| r.step_by(4096))
-            // create `PhysFrame` types from the start addresses
-            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
-
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.unused_4kib_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
-}
-
-/// Active Level 4 table.
-fn get_active_level_4_table(physical_offset: VirtAddr) -> &'static mut PageTable {
-    let (frame, _) = x86_64::registers::control::Cr3::read();
-
-    let start = frame.start_address();
-    let virt_start = physical_offset + start.as_u64();
-
-    // this is the place where the page table exists
-    unsafe { &mut *virt_start.as_mut_ptr::<PageTable>() }
-}
-
-pub fn init(physical_offset: VirtAddr) -> OffsetPageTable<'static> {
-    let table = get_active_level_4_table(physical_offset);
-    unsafe { OffsetPageTable::new(table, physical_offset) }
-}
@@ -0,0 +1,397 @@
+use crate::memory::{self, cow};
+use core::{
+    ops::{DerefMut, Range},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::MapToError, page_table::PageTableEntry, FrameAllocator, FrameDeallocator, Mapper,
+        OffsetPageTable, Page, PageTable, PageTableFlags, PageTableIndex, PhysFrame, Size4KiB,
+    },
+    VirtAddr,
+};
+
+/// User programs live in the upper half of the lower canonical half.
+/// The bootloader hands out level 4 entries starting from 0 for everything
+/// it maps (kernel, boot stack, physical memory), far below this range.
+pub const USER_SPACE_START: u64 = 0x0000_4000_0000_0000;
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// Level 4 entries covering `USER_SPACE_START..USER_SPACE_END`.
+const USER_LEVEL_4_ENTRIES: Range<usize> = 128..256;
+
+pub fn is_user_range(start: VirtAddr, len: u64) -> bool {
+    let start = start.as_u64();
+    start >= USER_SPACE_START
+        && start
+            .checked_add(len)
+            .map_or(false, |end| end <= USER_SPACE_END)
+}
+
+/// Page table frames owned by address spaces, level 4 tables included.
+static USER_TABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// How many frames the page tables of all address spaces take, not
+/// counting the kernel tables they share.
+pub fn user_table_frames() -> usize {
+    USER_TABLE_FRAMES.load(Ordering::Relaxed)
+}
+
+/// A level 4 page table that shares all kernel mappings with the
+/// kernel's own table and has a private user space.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user space.
+    ///
+    /// Only kernel level 4 entries existing at this point are shared,
+    /// so kernel mappings must be made inside already present entries.
+    pub fn new() -> Option<Self> {
+        let level_4_frame = memory::allocate_zeroed_frame()?;
+        let kernel_frame = memory::kernel_level_4_frame();
+        let kernel_table = unsafe { &*table_ptr(kernel_frame) };
+        let table = unsafe { &mut *table_ptr(level_4_frame) };
+
+        for (index, entry) in kernel_table.iter().enumerate() {
+            // skip the bootloader's recursive entry, it would map the kernel
+            // table instead of ours.
+            if USER_LEVEL_4_ENTRIES.contains(&index)
+                || entry.is_unused()
+                || entry.addr() == kernel_frame.start_address()
+            {
+                continue;
+            }
+            table[index] = entry.clone();
+        }
+
+        USER_TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
+        Some(AddressSpace { level_4_frame })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Loads this address space into CR3.
+    ///
+    /// # Safety
+    ///
+    /// The address space must outlive its activation.
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+
+    /// Switches back to the kernel's own page table.
+    pub fn activate_kernel() {
+        let (_, flags) = Cr3::read();
+        unsafe { Cr3::write(memory::kernel_level_4_frame(), flags) };
+    }
+
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe {
+            OffsetPageTable::new(
+                &mut *table_ptr(self.level_4_frame),
+                memory::physical_memory_offset(),
+            )
+        }
+    }
+
+    /// Backs a user page with a fresh zeroed frame.
+    ///
+    /// If the page is already mapped, its flags are merged with `flags`
+    /// instead, so that segments sharing a page get the union of their
+    /// permissions.
+    pub fn map_user_page(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<PhysFrame, MapToError<Size4KiB>> {
+        assert!(is_user_range(page.start_address(), page.size()));
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+        if let Some(entry) = self.entry_mut(page) {
+            let old = entry.flags();
+            let mut merged = old | flags;
+            if !(old & flags).contains(PageTableFlags::NO_EXECUTE) {
+                merged.remove(PageTableFlags::NO_EXECUTE);
+            }
+            if old.contains(cow::COW) {
+                // stays read-only until the first write copies it
+                merged.remove(PageTableFlags::WRITABLE);
+            }
+            entry.set_flags(merged);
+            let frame = entry.frame().expect("user page mapped to a huge page");
+            x86_64::instructions::tlb::flush(page.start_address());
+            return Ok(frame);
+        }
+
+        let frame = memory::allocate_zeroed_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        self.map_frame(page, frame, flags)?;
+        Ok(frame)
+    }
+
+    /// Maps a user page to the shared zero frame. It reads as zeros, and
+    /// gets a frame of its own on the first write if `flags` is writable.
+    pub fn map_zero_page(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(is_user_range(page.start_address(), page.size()));
+        let mut flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if flags.contains(PageTableFlags::WRITABLE) {
+            flags = (flags - PageTableFlags::WRITABLE) | cow::COW;
+        }
+        self.map_frame(page, cow::zero_frame(), flags)
+    }
+
+    /// Creates a copy of this address space that shares every user frame
+    /// with it. Writable pages become copy-on-write in both, so copying is
+    /// deferred until somebody writes.
+    pub fn fork(&mut self) -> Option<AddressSpace> {
+        let mut child = AddressSpace::new()?;
+        let mut failed = false;
+        let active = self.is_active();
+
+        self.for_each_user_entry(|page, entry| {
+            if failed {
+                return;
+            }
+            let frame = entry.frame().expect("huge page in user space");
+            let mut flags = entry.flags();
+            if flags.intersects(PageTableFlags::WRITABLE | cow::COW) {
+                flags = (flags - PageTableFlags::WRITABLE) | cow::COW;
+                entry.set_flags(flags);
+                if active {
+                    x86_64::instructions::tlb::flush(page.start_address());
+                }
+            }
+            cow::share(frame);
+            if child.map_frame(page, frame, flags).is_err() {
+                // the child's drop releases the reference again
+                cow::release(frame);
+                failed = true;
+            }
+        });
+
+        if failed {
+            None
+        } else {
+            Some(child)
+        }
+    }
+
+    /// Calls `f` for every present level 1 entry in user space.
+    fn for_each_user_entry(&mut self, mut f: impl FnMut(Page, &mut PageTableEntry)) {
+        let level_4 = unsafe { &mut *table_ptr(self.level_4_frame) };
+        for p4 in USER_LEVEL_4_ENTRIES {
+            let level_3 = match level_4[p4].frame() {
+                Ok(frame) => unsafe { &mut *table_ptr(frame) },
+                Err(_) => continue,
+            };
+            for p3 in 0..512 {
+                let level_2 = match level_3[p3].frame() {
+                    Ok(frame) => unsafe { &mut *table_ptr(frame) },
+                    Err(_) => continue,
+                };
+                for p2 in 0..512 {
+                    let level_1 = match level_2[p2].frame() {
+                        Ok(frame) => unsafe { &mut *table_ptr(frame) },
+                        Err(_) => continue,
+                    };
+                    for p1 in 0..512 {
+                        let entry = &mut level_1[p1];
+                        if !entry.flags().contains(PageTableFlags::PRESENT) {
+                            continue;
+                        }
+                        let page = Page::from_page_table_indices(
+                            PageTableIndex::new(p4 as u16),
+                            PageTableIndex::new(p3 as u16),
+                            PageTableIndex::new(p2 as u16),
+                            PageTableIndex::new(p1 as u16),
+                        );
+                        f(page, entry);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Maps `page` to `frame`. Intermediate tables are always writable, so
+    /// that the flags of the last level alone decide, even after a
+    /// copy-on-write page turned writable.
+    fn map_frame(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let mut frame_allocator = TableCounter(memory::frame_allocator());
+        unsafe {
+            self.mapper()
+                .map_to_with_table_flags(page, frame, flags, table_flags, &mut frame_allocator)?
+                .flush();
+        }
+        Ok(())
+    }
+
+    /// The level 1 entry of a mapped 4 KiB page.
+    pub fn entry_mut(&mut self, page: Page) -> Option<&mut PageTableEntry> {
+        unsafe { leaf_entry(self.level_4_frame, page) }
+    }
+
+    /// Copies `data` to `addr` through the physical memory mapping,
+    /// so this works whether or not the address space is active.
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), ()> {
+        self.for_each_chunk(addr, data.len(), |dst, offset, len| unsafe {
+            core::ptr::copy_nonoverlapping(data[offset..].as_ptr(), dst, len)
+        })
+    }
+
+    /// Fills `len` bytes at `addr` with zeros.
+    pub fn zero(&mut self, addr: VirtAddr, len: usize) -> Result<(), ()> {
+        self.for_each_chunk(addr, len, |dst, _, len| unsafe {
+            core::ptr::write_bytes(dst, 0, len)
+        })
+    }
+
+    /// Splits `addr..addr + len` at page boundaries and calls `f` with a
+    /// kernel pointer to each piece, its offset in the range and its length.
+    fn for_each_chunk(
+        &mut self,
+        addr: VirtAddr,
+        len: usize,
+        mut f: impl FnMut(*mut u8, usize, usize),
+    ) -> Result<(), ()> {
+        let mut done = 0;
+        while done < len {
+            let current = addr + done;
+            let page = Page::<Size4KiB>::containing_address(current);
+            let page_offset = (current - page.start_address()) as usize;
+            let n = (len - done).min(page.size() as usize - page_offset);
+
+            let active = self.is_active();
+            let entry = self.entry_mut(page).ok_or(())?;
+            if entry.flags().contains(cow::COW) {
+                // never write to a frame somebody else still sees
+                if !cow::unshare(entry) {
+                    return Err(());
+                }
+                if active {
+                    x86_64::instructions::tlb::flush(page.start_address());
+                }
+            }
+            let frame = entry.frame().map_err(|_| ())?;
+            let dst = memory::phys_to_virt(frame.start_address()) + page_offset;
+            f(dst.as_mut_ptr(), done, n);
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Returns every frame of the user space, the page tables mapping it
+    /// and the level 4 table itself. Kernel tables are shared and stay.
+    fn drop(&mut self) {
+        if self.is_active() {
+            Self::activate_kernel();
+        }
+
+        let table = unsafe { &*table_ptr(self.level_4_frame) };
+        let mut frame_allocator = memory::frame_allocator();
+        for entry in table
+            .iter()
+            .take(USER_LEVEL_4_ENTRIES.end)
+            .skip(USER_LEVEL_4_ENTRIES.start)
+        {
+            if let Ok(frame) = entry.frame() {
+                unsafe { free_table(frame, 3, &mut *frame_allocator) };
+            }
+        }
+        unsafe { frame_allocator.deallocate_frame(self.level_4_frame) };
+        USER_TABLE_FRAMES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Hands out frames for new page tables and counts them.
+struct TableCounter<A>(A);
+
+unsafe impl<A: DerefMut<Target = memory::BootInfoFrameAllocator>> FrameAllocator<Size4KiB>
+    for TableCounter<A>
+{
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.0.allocate_frame()?;
+        USER_TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
+        Some(frame)
+    }
+}
+
+/// Frees a level `level` table, everything it maps and the tables below it.
+unsafe fn free_table(
+    table_frame: PhysFrame,
+    level: u8,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let table = &*table_ptr(table_frame);
+    for entry in table.iter() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        // user space is only ever mapped with 4 KiB pages
+        let frame = entry.frame().expect("huge page in user space");
+        if level == 1 {
+            if cow::release(frame) {
+                frame_allocator.deallocate_frame(frame);
+            }
+        } else {
+            free_table(frame, level - 1, frame_allocator);
+        }
+    }
+    frame_allocator.deallocate_frame(table_frame);
+    USER_TABLE_FRAMES.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// The level 1 entry of a mapped 4 KiB page in the table hierarchy rooted
+/// at `level_4_frame`.
+///
+/// # Safety
+///
+/// The tables must not be changed by anybody else while the entry is used.
+pub(crate) unsafe fn leaf_entry(
+    level_4_frame: PhysFrame,
+    page: Page,
+) -> Option<&'static mut PageTableEntry> {
+    let mut table = &mut *table_ptr(level_4_frame);
+    let indexes = [page.p4_index(), page.p3_index(), page.p2_index()];
+    for &index in indexes.iter() {
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT)
+            || entry.flags().contains(PageTableFlags::HUGE_PAGE)
+        {
+            return None;
+        }
+        table = &mut *table_ptr(entry.frame().ok()?);
+    }
+
+    let entry = &mut table[page.p1_index()];
+    if entry.flags().contains(PageTableFlags::PRESENT) {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
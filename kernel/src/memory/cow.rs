@@ -0,0 +1,128 @@
+//! Copy-on-write sharing of user frames.
+//!
+//! A shared page is mapped read-only with the `COW` bit set in every address
+//! space using it. The first write faults, and `handle_write_fault` gives the
+//! writer a private copy (or just makes the page writable again, if nobody
+//! else uses the frame anymore).
+
+use crate::memory::{self, address_space};
+use alloc::collections::BTreeMap;
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::{
+    instructions::tlb,
+    registers::control::Cr3,
+    structures::paging::{
+        page_table::PageTableEntry, FrameAllocator, FrameDeallocator, Page, PageSize,
+        PageTableFlags, PhysFrame, Size4KiB,
+    },
+    VirtAddr,
+};
+
+/// Software bit marking a read-only mapping that becomes writable
+/// by copying the frame.
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+lazy_static::lazy_static! {
+    /// Reference counts of frames mapped more than once. Frames that
+    /// aren't in here have exactly one user.
+    static ref SHARED_FRAMES: Mutex<BTreeMap<PhysFrame, usize>> = Mutex::new(BTreeMap::new());
+}
+
+/// A frame full of zeros, shared by every page that was never written to.
+static ZERO_FRAME: OnceCell<PhysFrame> = OnceCell::uninit();
+
+pub fn zero_frame() -> PhysFrame {
+    ZERO_FRAME.init_once(|| memory::allocate_zeroed_frame().expect("no memory for the zero page"));
+    *ZERO_FRAME.try_get().expect("just initialized")
+}
+
+fn is_zero_frame(frame: PhysFrame) -> bool {
+    ZERO_FRAME.try_get().map_or(false, |&zero| zero == frame)
+}
+
+/// Adds a reference to `frame`.
+pub fn share(frame: PhysFrame) {
+    if is_zero_frame(frame) {
+        return;
+    }
+    *SHARED_FRAMES.lock().entry(frame).or_insert(1) += 1;
+}
+
+/// Drops a reference to `frame`. Returns true if it was the last one and
+/// the caller must free the frame.
+pub fn release(frame: PhysFrame) -> bool {
+    if is_zero_frame(frame) {
+        return false;
+    }
+    let mut shared = SHARED_FRAMES.lock();
+    match shared.get_mut(&frame) {
+        Some(count) => {
+            *count -= 1;
+            if *count == 1 {
+                shared.remove(&frame);
+            }
+            false
+        }
+        None => true,
+    }
+}
+
+/// How many mappings use `frame`.
+pub fn ref_count(frame: PhysFrame) -> usize {
+    SHARED_FRAMES.lock().get(&frame).copied().unwrap_or(1)
+}
+
+/// Resolves a write to a copy-on-write page of the active address space.
+///
+/// Returns false if `addr` is not a copy-on-write page, which makes the
+/// fault a real one.
+pub fn handle_write_fault(addr: VirtAddr) -> bool {
+    if !address_space::is_user_range(addr, 1) {
+        return false;
+    }
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let level_4_frame = Cr3::read().0;
+    let entry = match unsafe { address_space::leaf_entry(level_4_frame, page) } {
+        Some(entry) => entry,
+        None => return false,
+    };
+    if !entry.flags().contains(COW) || !unshare(entry) {
+        return false;
+    }
+    tlb::flush(page.start_address());
+    true
+}
+
+/// Gives the copy-on-write page of `entry` a frame of its own and makes it
+/// writable. The caller flushes the TLB.
+///
+/// Returns false if we ran out of memory.
+pub(crate) fn unshare(entry: &mut PageTableEntry) -> bool {
+    let old = entry.frame().expect("COW entry without frame");
+    let flags = (entry.flags() - COW) | PageTableFlags::WRITABLE;
+
+    if !is_zero_frame(old) && ref_count(old) == 1 {
+        // everybody else already copied it
+        entry.set_flags(flags);
+        return true;
+    }
+
+    let new: PhysFrame = match memory::frame_allocator().allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            memory::phys_to_virt(old.start_address()).as_ptr::<u8>(),
+            memory::phys_to_virt(new.start_address()).as_mut_ptr::<u8>(),
+            Size4KiB::SIZE as usize,
+        );
+    }
+    entry.set_addr(new.start_address(), flags);
+
+    if release(old) {
+        unsafe { memory::frame_allocator().deallocate_frame(old) };
+    }
+    true
+}
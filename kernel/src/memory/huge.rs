@@ -0,0 +1,431 @@
+//! Kernel mappings with 2 MiB and 1 GiB pages.
+//!
+//! One huge page takes a single TLB entry and no level 1 table, so large,
+//! long-lived regions such as the heap use them wherever the range is
+//! aligned. Every function falls back to smaller pages for the unaligned
+//! ends, or when physical memory is too fragmented for a large frame.
+//!
+//! Device memory goes through `MmioRegion::map_with`, which places large
+//! regions, like a linear framebuffer, so that they get 2 MiB pages too.
+//! The bootloader leaves us in VGA text mode, whose buffer fits in a page.
+//! Large frames keep their size when freed, so they can be mapped as huge
+//! pages again.
+//!
+//! The physical memory mapping is made of huge pages as well. Where part of
+//! it needs other caching, `set_physical_cache` splits them.
+
+use crate::{cpu, memory};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, MapperAllSizes, TranslateResult},
+        page_table::PageTableEntry,
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTable, PageTableFlags,
+        PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// The flags selecting the memory type of a page, as long as the PAT bit
+/// stays clear.
+fn cache_flags() -> PageTableFlags {
+    PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+}
+
+/// Whether the CPU can map 1 GiB pages.
+pub fn gigantic_pages_supported() -> bool {
+    cpu::features().page_1gb
+}
+
+/// The largest page size that starts at `addr` and fits in `len` bytes.
+fn page_size_at(addr: u64, len: u64) -> u64 {
+    if gigantic_pages_supported() && addr % Size1GiB::SIZE == 0 && len >= Size1GiB::SIZE {
+        Size1GiB::SIZE
+    } else if addr % Size2MiB::SIZE == 0 && len >= Size2MiB::SIZE {
+        Size2MiB::SIZE
+    } else {
+        Size4KiB::SIZE
+    }
+}
+
+/// Maps `size` bytes at `start` (both page aligned) to newly allocated
+/// frames, using the largest pages possible.
+///
+/// On failure, whatever was mapped so far stays mapped; `unmap_range` it.
+pub fn map_range(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    assert!(start.is_aligned(Size4KiB::SIZE) && size % Size4KiB::SIZE == 0);
+    let flags = flags | PageTableFlags::PRESENT;
+    let mut page_table = memory::kernel_page_table();
+    let mut frame_allocator = memory::frame_allocator();
+
+    let mut addr = start.as_u64();
+    let end = addr + size;
+    while addr < end {
+        let mut page_size = page_size_at(addr, end - addr);
+        loop {
+            let virt = VirtAddr::new(addr);
+            let mapped = unsafe {
+                match page_size {
+                    Size1GiB::SIZE => match frame_allocator.allocate_frame() {
+                        Some(frame) => map::<Size1GiB, _, _>(
+                            &mut *page_table,
+                            &mut *frame_allocator,
+                            virt,
+                            frame,
+                            flags,
+                        ),
+                        None => Err(MapToError::FrameAllocationFailed),
+                    },
+                    Size2MiB::SIZE => match frame_allocator.allocate_frame() {
+                        Some(frame) => map::<Size2MiB, _, _>(
+                            &mut *page_table,
+                            &mut *frame_allocator,
+                            virt,
+                            frame,
+                            flags,
+                        ),
+                        None => Err(MapToError::FrameAllocationFailed),
+                    },
+                    _ => {
+                        let frame = frame_allocator
+                            .allocate_frame()
+                            .ok_or(MapToError::FrameAllocationFailed)?;
+                        map::<Size4KiB, _, _>(
+                            &mut *page_table,
+                            &mut *frame_allocator,
+                            virt,
+                            frame,
+                            flags,
+                        )
+                    }
+                }
+            };
+            match mapped {
+                Ok(()) => break,
+                // no large frame left, try again with smaller ones
+                Err(MapToError::FrameAllocationFailed) if page_size != Size4KiB::SIZE => {
+                    page_size = if page_size == Size1GiB::SIZE {
+                        page_size_at(addr, Size1GiB::SIZE - 1)
+                    } else {
+                        Size4KiB::SIZE
+                    };
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        addr += page_size;
+    }
+    Ok(())
+}
+
+/// Maps `size` bytes of physical memory at `phys` to `virt`, e.g. a
+/// framebuffer or device memory. Huge pages are used where both addresses
+/// are aligned to them.
+pub fn map_physical_range(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    assert!(virt.is_aligned(Size4KiB::SIZE) && phys.is_aligned(Size4KiB::SIZE));
+    let flags = flags | PageTableFlags::PRESENT;
+    let mut page_table = memory::kernel_page_table();
+    let mut frame_allocator = memory::frame_allocator();
+
+    let size = (size + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1);
+    let mut offset = 0;
+    while offset < size {
+        let v = virt + offset;
+        let p = phys + offset;
+        // physical and virtual address must agree on the page size
+        let page_size =
+            page_size_at(v.as_u64(), size - offset).min(page_size_at(p.as_u64(), size - offset));
+        unsafe {
+            match page_size {
+                Size1GiB::SIZE => map::<Size1GiB, _, _>(
+                    &mut *page_table,
+                    &mut *frame_allocator,
+                    v,
+                    PhysFrame::containing_address(p),
+                    flags,
+                ),
+                Size2MiB::SIZE => map::<Size2MiB, _, _>(
+                    &mut *page_table,
+                    &mut *frame_allocator,
+                    v,
+                    PhysFrame::containing_address(p),
+                    flags,
+                ),
+                _ => map::<Size4KiB, _, _>(
+                    &mut *page_table,
+                    &mut *frame_allocator,
+                    v,
+                    PhysFrame::containing_address(p),
+                    flags,
+                ),
+            }?;
+        }
+        offset += page_size;
+    }
+    Ok(())
+}
+
+/// Unmaps `size` bytes at `start`, whatever page sizes they are mapped
+/// with. Frames are freed if `free_frames` is set, i.e. if they came from
+/// `map_range` rather than `map_physical_range`.
+pub fn unmap_range(start: VirtAddr, size: u64, free_frames: bool) {
+    let mut page_table = memory::kernel_page_table();
+    let mut frame_allocator = memory::frame_allocator();
+
+    let mut addr = start.as_u64();
+    let end = addr + size;
+    while addr < end {
+        let virt = VirtAddr::new(addr);
+        addr += match page_table.translate(virt) {
+            TranslateResult::Frame1GiB { .. } => {
+                let page = Page::<Size1GiB>::containing_address(virt);
+                let (frame, flush) = page_table.unmap(page).expect("just translated");
+                flush.flush();
+                if free_frames {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+                Size1GiB::SIZE
+            }
+            TranslateResult::Frame2MiB { .. } => {
+                let page = Page::<Size2MiB>::containing_address(virt);
+                let (frame, flush) = page_table.unmap(page).expect("just translated");
+                flush.flush();
+                if free_frames {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+                Size2MiB::SIZE
+            }
+            TranslateResult::Frame4KiB { .. } => {
+                let page = Page::<Size4KiB>::containing_address(virt);
+                let (frame, flush) = page_table.unmap(page).expect("just translated");
+                flush.flush();
+                if free_frames {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+                Size4KiB::SIZE
+            }
+            TranslateResult::PageNotMapped | TranslateResult::InvalidFrameAddress(_) => {
+                Size4KiB::SIZE
+            }
+        };
+    }
+}
+
+/// Gives the `count` frames from `first` the caching selected by `cache`
+/// (see `dma::CacheMode::flags`) in the physical memory mapping, so that
+/// they aren't mapped with two memory types at once. Huge pages in the way
+/// are split and stay split; ones that already have the right caching are
+/// left alone, so going back to write-back never allocates.
+///
+/// The caller flushes the caches if lines of the frames may be cached.
+pub(crate) fn set_physical_cache(
+    first: PhysFrame,
+    count: u64,
+    cache: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let cache = cache & cache_flags();
+    let page_table = memory::kernel_page_table();
+    let mut frame_allocator = memory::frame_allocator();
+    let level_4_frame = memory::kernel_level_4_frame();
+
+    let mut result = Ok(());
+    for frame in PhysFrame::range(first, first + count) {
+        let virt = memory::phys_to_virt(frame.start_address());
+        let page = Page::<Size4KiB>::containing_address(virt);
+        // the page table lock keeps everybody else out of the tables
+        match unsafe { split_to_leaf(level_4_frame, page, cache, &mut *frame_allocator) } {
+            Ok(Some(entry)) => entry.set_flags(entry.flags() - cache_flags() | cache),
+            Ok(None) => {}
+            Err(err) => {
+                result = Err(err);
+                break;
+            }
+        }
+    }
+    drop(frame_allocator);
+    drop(page_table);
+    // split pages may be cached under any of their addresses
+    x86_64::instructions::tlb::flush_all();
+    result
+}
+
+/// The level 1 entry mapping `page`, splitting the huge pages above it.
+/// `None` if a huge page on the way already has the caching `cache`.
+///
+/// # Safety
+///
+/// Nobody else may change the tables meanwhile.
+unsafe fn split_to_leaf(
+    level_4_frame: PhysFrame,
+    page: Page,
+    cache: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<Option<&'static mut PageTableEntry>, MapToError<Size4KiB>> {
+    let level_4 = &mut *table_ptr(level_4_frame);
+    // level 4 entries are never huge
+    let mut table = &mut *table_ptr(level_4[page.p4_index()].frame().expect("not mapped"));
+    let levels = [
+        (page.p3_index(), Size2MiB::SIZE),
+        (page.p2_index(), Size4KiB::SIZE),
+    ];
+    for &(index, smaller) in levels.iter() {
+        let entry = &mut table[index];
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            if entry.flags() & cache_flags() == cache {
+                return Ok(None);
+            }
+            split(entry, smaller, frame_allocator)?;
+        }
+        table = &mut *table_ptr(entry.frame().expect("not mapped"));
+    }
+    Ok(Some(&mut table[page.p1_index()]))
+}
+
+/// Replaces the huge page of `entry` by a table of pages of size
+/// `smaller` mapping the same memory with the same flags.
+unsafe fn split(
+    entry: &mut PageTableEntry,
+    smaller: u64,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let table_frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let table = &mut *table_ptr(table_frame);
+    let mut flags = entry.flags();
+    if smaller == Size4KiB::SIZE {
+        flags.remove(PageTableFlags::HUGE_PAGE);
+    }
+    for (i, small) in table.iter_mut().enumerate() {
+        small.set_addr(entry.addr() + i as u64 * smaller, flags);
+    }
+    // the permissions of the huge page now come from the new entries
+    let table_flags = entry.flags() - PageTableFlags::HUGE_PAGE - cache_flags();
+    entry.set_addr(table_frame.start_address(), table_flags);
+    Ok(())
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// Maps a single page of size `S`. Errors are reported as 4 KiB errors, so
+/// that callers mixing page sizes have a single error type.
+unsafe fn map<S: PageSize, M: Mapper<S>, A: FrameAllocator<Size4KiB>>(
+    page_table: &mut M,
+    frame_allocator: &mut A,
+    virt: VirtAddr,
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let page = Page::<S>::containing_address(virt);
+    match page_table.map_to(page, frame, flags, frame_allocator) {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(MapToError::FrameAllocationFailed) => Err(MapToError::FrameAllocationFailed),
+        Err(MapToError::ParentEntryHugePage) => Err(MapToError::ParentEntryHugePage),
+        Err(MapToError::PageAlreadyMapped(frame)) => Err(MapToError::PageAlreadyMapped(
+            PhysFrame::containing_address(frame.start_address()),
+        )),
+    }
+}
+
+/// Virtual space for `LargeBuffer`s and other mappings made on demand,
+/// inside the level 4 entry of the kernel image like the heap and the
+/// kernel stacks.
+pub const LARGE_BUFFERS_START: u64 = 0x_0020_0000_0000;
+pub const LARGE_BUFFERS_END: u64 = 0x_0040_0000_0000;
+
+/// Used ranges of the buffer region, sorted by address.
+static BUFFER_RANGES: Mutex<Vec<(u64, u64)>> = Mutex::new(Vec::new());
+
+/// A zeroed, page aligned kernel buffer with a mapping of its own, backed
+/// by huge pages as far as its size allows. Unmapped and freed on drop.
+///
+/// Meant for buffers of a megabyte or more, which would waste the heap.
+pub struct LargeBuffer {
+    start: VirtAddr,
+    size: u64,
+}
+
+impl LargeBuffer {
+    /// Maps a buffer of `size` bytes, rounded up to whole pages.
+    pub fn new(size: usize) -> Result<Self, MapToError<Size4KiB>> {
+        let size = (size as u64 + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1);
+        assert!(size > 0, "empty buffer");
+        // align so the bulk of the buffer can use the largest pages
+        let align = page_size_at(0, size);
+        let start = reserve(size, align).ok_or(MapToError::FrameAllocationFailed)?;
+
+        // on failure, drop unmaps what is mapped so far
+        let buffer = LargeBuffer { start, size };
+        map_range(
+            start,
+            size,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )?;
+        unsafe { core::ptr::write_bytes(start.as_mut_ptr::<u8>(), 0, size as usize) };
+        Ok(buffer)
+    }
+
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.start.as_ptr(), self.size()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.start.as_mut_ptr(), self.size()) }
+    }
+}
+
+impl Drop for LargeBuffer {
+    fn drop(&mut self) {
+        unmap_range(self.start, self.size, true);
+        unreserve(self.start);
+    }
+}
+
+/// Finds the first `align`ed gap of `size` bytes in the buffer region and
+/// marks it used. Nothing is mapped there yet.
+pub(crate) fn reserve(size: u64, align: u64) -> Option<VirtAddr> {
+    let mut ranges = BUFFER_RANGES.lock();
+    let mut candidate = LARGE_BUFFERS_START;
+    let mut index = 0;
+    for &(start, len) in ranges.iter() {
+        if candidate + size <= start {
+            break;
+        }
+        candidate = (start + len + align - 1) & !(align - 1);
+        index += 1;
+    }
+    if candidate + size > LARGE_BUFFERS_END {
+        return None;
+    }
+    ranges.insert(index, (candidate, size));
+    Some(VirtAddr::new(candidate))
+}
+
+/// Gives back a range returned by `reserve`, after unmapping it.
+pub(crate) fn unreserve(start: VirtAddr) {
+    let start = start.as_u64();
+    BUFFER_RANGES.lock().retain(|&(used, _)| used != start);
+}
@@ -0,0 +1,171 @@
+//! Where the memory went: the boot memory map and current usage.
+
+use crate::{kalloc, memory};
+use bootloader::bootinfo::MemoryRegionType;
+use core::fmt;
+use x86_64::structures::paging::{PageTable, PhysFrame};
+
+/// Snapshot of physical memory and heap usage, see `meminfo`.
+#[derive(Debug, Clone, Copy)]
+pub struct MemInfo {
+    /// RAM the machine has, i.e. everything the firmware didn't reserve.
+    pub total: u64,
+    /// RAM the frame allocator manages.
+    pub usable: u64,
+    /// Address space the firmware reserved or marked bad.
+    pub reserved: u64,
+    pub frames_total: usize,
+    pub frames_in_use: usize,
+    pub heap_size: usize,
+    pub heap_used: usize,
+    /// Frames taken by page tables, the kernel's and those of every
+    /// address space.
+    pub page_table_frames: usize,
+}
+
+/// Collects the current memory usage.
+pub fn meminfo() -> MemInfo {
+    let mut total = 0;
+    let mut usable = 0;
+    let mut reserved = 0;
+    for region in memory::memory_map().iter() {
+        let size = region.range.end_addr() - region.range.start_addr();
+        match region.region_type {
+            MemoryRegionType::Reserved | MemoryRegionType::BadMemory => reserved += size,
+            MemoryRegionType::Usable => {
+                usable += size;
+                total += size;
+            }
+            _ => total += size,
+        }
+    }
+
+    let (frames_total, frames_in_use) = {
+        let frame_allocator = memory::frame_allocator();
+        (
+            frame_allocator.total_frames(),
+            frame_allocator.frames_in_use(),
+        )
+    };
+    let (heap_used, heap_size) = kalloc::heap_usage();
+
+    MemInfo {
+        total,
+        usable,
+        reserved,
+        frames_total,
+        frames_in_use,
+        heap_size,
+        heap_used,
+        page_table_frames: kernel_table_frames() + memory::address_space::user_table_frames(),
+    }
+}
+
+impl fmt::Display for MemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "memory: {} total, {} usable, {} reserved",
+            Size(self.total),
+            Size(self.usable),
+            Size(self.reserved)
+        )?;
+        writeln!(
+            f,
+            "frames: {} of {} in use ({})",
+            self.frames_in_use,
+            self.frames_total,
+            Size(self.frames_in_use as u64 * 4096)
+        )?;
+        writeln!(
+            f,
+            "heap: {} of {} in use",
+            Size(self.heap_used as u64),
+            Size(self.heap_size as u64)
+        )?;
+        write!(
+            f,
+            "page tables: {} frames ({})",
+            self.page_table_frames,
+            Size(self.page_table_frames as u64 * 4096)
+        )
+    }
+}
+
+/// The bootloader's memory map, one region per line.
+pub struct MemoryMapReport;
+
+impl fmt::Display for MemoryMapReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for region in memory::memory_map().iter() {
+            let start = region.range.start_addr();
+            let end = region.range.end_addr();
+            writeln!(
+                f,
+                "  {:#014x}-{:#014x} {:>9} {:?}",
+                start,
+                end,
+                Size(end - start),
+                region.region_type
+            )?;
+        }
+        let info = meminfo();
+        write!(
+            f,
+            "  {} total, {} usable, {} reserved",
+            Size(info.total),
+            Size(info.usable),
+            Size(info.reserved)
+        )
+    }
+}
+
+/// Bytes in the largest unit that keeps them a whole number.
+struct Size(u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let units = [("GiB", 1 << 30), ("MiB", 1 << 20), ("KiB", 1 << 10)];
+        for &(unit, size) in units.iter() {
+            if self.0 >= size && self.0 % size == 0 {
+                return f.pad(&alloc::format!("{} {}", self.0 / size, unit));
+            }
+        }
+        f.pad(&alloc::format!("{} B", self.0))
+    }
+}
+
+/// Counts the kernel's page tables, which every address space shares.
+fn kernel_table_frames() -> usize {
+    let level_4_frame = memory::kernel_level_4_frame();
+    // keeps the tables from changing under us
+    let _page_table = memory::kernel_page_table();
+    let table = unsafe { &*table_ptr(level_4_frame) };
+    let mut count = 1;
+    for entry in table.iter() {
+        match entry.frame() {
+            // skip the bootloader's recursive entry
+            Ok(frame) if frame != level_4_frame => count += count_tables(frame, 3),
+            _ => {}
+        }
+    }
+    count
+}
+
+/// Counts the level `level` table in `frame` and the tables below it.
+fn count_tables(frame: PhysFrame, level: u8) -> usize {
+    if level == 1 {
+        return 1;
+    }
+    let table = unsafe { &*table_ptr(frame) };
+    1 + table
+        .iter()
+        // huge pages have no frame, only tables do
+        .filter_map(|entry| entry.frame().ok())
+        .map(|frame| count_tables(frame, level - 1))
+        .sum::<usize>()
+}
+
+fn table_ptr(frame: PhysFrame) -> *const PageTable {
+    memory::phys_to_virt(frame.start_address()).as_ptr()
+}
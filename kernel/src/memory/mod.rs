@@ -0,0 +1,424 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use conquer_once::spin::OnceCell;
+use spin::{Mutex, MutexGuard};
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, OffsetPageTable, PageSize, PageTable, PageTableFlags,
+        PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+pub mod address_space;
+pub mod cow;
+pub mod huge;
+pub mod info;
+pub mod protection;
+pub mod stack;
+
+pub use address_space::AddressSpace;
+pub use info::{meminfo, MemInfo};
+
+/// Where the bootloader mapped the whole physical memory.
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+
+/// The level 4 table the bootloader left us with.
+/// Every address space shares its kernel entries.
+static KERNEL_LEVEL_4_FRAME: OnceCell<PhysFrame> = OnceCell::uninit();
+
+/// The memory map the bootloader passed us.
+static MEMORY_MAP: OnceCell<&'static MemoryMap> = OnceCell::uninit();
+
+static KERNEL_PAGE_TABLE: OnceCell<Mutex<OffsetPageTable<'static>>> = OnceCell::uninit();
+static FRAME_ALLOCATOR: OnceCell<Mutex<BootInfoFrameAllocator>> = OnceCell::uninit();
+
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    next: usize,
+
+    /// Frames given back by `deallocate_frame`, by size. Large frames keep
+    /// their size, so they can be handed out as large frames again.
+    free_4kib: FreeList,
+    free_2mib: FreeList,
+    free_1gib: FreeList,
+}
+
+/// Free frames of one size. Each free frame stores the address of the next
+/// one in its first 8 bytes, 0 ends the list (frame 0 is never usable
+/// memory).
+struct FreeList {
+    head: u64,
+    len: usize,
+}
+
+impl FreeList {
+    const fn new() -> Self {
+        FreeList { head: 0, len: 0 }
+    }
+
+    fn next_ptr(addr: u64) -> *mut u64 {
+        phys_to_virt(PhysAddr::new(addr)).as_mut_ptr()
+    }
+
+    /// # Safety
+    ///
+    /// The frame at `addr` must be free.
+    unsafe fn push(&mut self, addr: PhysAddr) {
+        *Self::next_ptr(addr.as_u64()) = self.head;
+        self.head = addr.as_u64();
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<PhysAddr> {
+        self.take(|_| true)
+    }
+
+    /// Unlinks the first frame `fits` accepts.
+    fn take(&mut self, fits: impl Fn(PhysAddr) -> bool) -> Option<PhysAddr> {
+        let mut previous = None;
+        let mut addr = self.head;
+        while addr != 0 {
+            let next = unsafe { *Self::next_ptr(addr) };
+            if fits(PhysAddr::new(addr)) {
+                match previous {
+                    Some(previous) => unsafe { *Self::next_ptr(previous) = next },
+                    None => self.head = next,
+                }
+                self.len -= 1;
+                return Some(PhysAddr::new(addr));
+            }
+            previous = Some(addr);
+            addr = next;
+        }
+        None
+    }
+}
+
+impl BootInfoFrameAllocator {
+    pub fn init(memory_map: &'static MemoryMap) -> Self {
+        BootInfoFrameAllocator {
+            memory_map,
+            next: 0,
+            free_4kib: FreeList::new(),
+            free_2mib: FreeList::new(),
+            free_1gib: FreeList::new(),
+        }
+    }
+
+    /// Number of frames the allocator manages.
+    pub fn total_frames(&self) -> usize {
+        self.memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| ((r.range.end_addr() - r.range.start_addr()) / 4096) as usize)
+            .sum()
+    }
+
+    /// Number of frames handed out and not given back.
+    pub fn frames_in_use(&self) -> usize {
+        let free = self.free_4kib.len
+            + self.free_2mib.len * (Size2MiB::SIZE / Size4KiB::SIZE) as usize
+            + self.free_1gib.len * (Size1GiB::SIZE / Size4KiB::SIZE) as usize;
+        self.next.min(self.total_frames()) - free
+    }
+
+    fn unused_4kib_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        self.memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            // map each region to its address range
+            .map(|r| r.range.start_addr()..r.range.end_addr())
+            // transform to an iterator of frame start addresses
+            .flat_map(|r| r.step_by(4096))
+            // create `PhysFrame` types from the start addresses
+            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// Takes `S::SIZE` bytes of contiguous frames starting at an `S::SIZE`
+    /// boundary: a freed large frame if there is one, else frames never
+    /// handed out, else a part of a freed larger frame.
+    fn allocate_aligned<S: PageSize>(&mut self) -> Option<PhysFrame<S>> {
+        let addr = match S::SIZE {
+            Size1GiB::SIZE => self.free_1gib.pop(),
+            _ => self.free_2mib.pop(),
+        };
+        if let Some(addr) = addr {
+            return Some(PhysFrame::containing_address(addr));
+        }
+        let count = (S::SIZE / Size4KiB::SIZE) as usize;
+        if let Some(frame) = self.allocate_unused(count, S::SIZE, u64::MAX) {
+            return Some(PhysFrame::containing_address(frame.start_address()));
+        }
+        if S::SIZE != Size2MiB::SIZE {
+            return None;
+        }
+        let addr = self.free_1gib.pop()?;
+        unsafe { self.free_range(addr + Size2MiB::SIZE, addr + Size1GiB::SIZE) };
+        Some(PhysFrame::containing_address(addr))
+    }
+
+    /// Gives back the free frames in `start..end`, as large frames where
+    /// they are aligned.
+    ///
+    /// # Safety
+    ///
+    /// The frames must be free.
+    unsafe fn free_range(&mut self, start: PhysAddr, end: PhysAddr) {
+        let mut addr = start;
+        while addr < end {
+            let left = end - addr;
+            if addr.is_aligned(Size1GiB::SIZE) && left >= Size1GiB::SIZE {
+                self.free_1gib.push(addr);
+                addr += Size1GiB::SIZE;
+            } else if addr.is_aligned(Size2MiB::SIZE) && left >= Size2MiB::SIZE {
+                self.free_2mib.push(addr);
+                addr += Size2MiB::SIZE;
+            } else {
+                self.free_4kib.push(addr);
+                addr += Size4KiB::SIZE;
+            }
+        }
+    }
+
+    /// Takes `count` physically contiguous frames, the first aligned to
+    /// `align` bytes and the last ending at or below `limit`.
+    ///
+    /// Only whole large frames that were freed and frames never handed out
+    /// before are considered, so this gets harder the longer the system
+    /// runs.
+    pub fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        align: u64,
+        limit: u64,
+    ) -> Option<PhysFrame> {
+        let size = count as u64 * Size4KiB::SIZE;
+        let fits = |addr: PhysAddr| {
+            addr.as_u64()
+                .checked_add(size)
+                .map_or(false, |end| end <= limit)
+        };
+        // the smallest kind of freed large frame that can hold the frames
+        let large = if size <= Size2MiB::SIZE && align <= Size2MiB::SIZE {
+            Size2MiB::SIZE
+        } else if size <= Size1GiB::SIZE && align <= Size1GiB::SIZE {
+            Size1GiB::SIZE
+        } else {
+            0
+        };
+        let addr = match large {
+            Size2MiB::SIZE => self.free_2mib.take(fits),
+            Size1GiB::SIZE => self.free_1gib.take(fits),
+            _ => None,
+        };
+        if let Some(addr) = addr {
+            // the rest of the large frame stays free
+            unsafe { self.free_range(addr + size, addr + large) };
+            return Some(PhysFrame::containing_address(addr));
+        }
+        self.allocate_unused(count, align, limit)
+    }
+
+    /// Like `allocate_contiguous`, but only from frames never handed out.
+    /// The frames skipped to get there go to the free list.
+    fn allocate_unused(&mut self, count: usize, align: u64, limit: u64) -> Option<PhysFrame> {
+        let mut run: Option<(usize, u64)> = None;
+        let mut run_len = 0;
+        let mut found = None;
+        for (index, frame) in self.unused_4kib_frames().enumerate().skip(self.next) {
+            let addr = frame.start_address().as_u64();
+            if addr + Size4KiB::SIZE > limit {
+                break;
+            }
+            let contiguous = run.map_or(false, |(_, start)| {
+                addr == start + run_len as u64 * Size4KiB::SIZE
+            });
+            if !contiguous {
+                run_len = 0;
+                run = if addr % align == 0 {
+                    Some((index, addr))
+                } else {
+                    None
+                };
+                if run.is_none() {
+                    continue;
+                }
+            }
+            run_len += 1;
+            if run_len == count {
+                found = run;
+                break;
+            }
+        }
+
+        let (index, addr) = found?;
+        let skipped = index - self.next;
+        for frame in self.unused_4kib_frames().skip(self.next).take(skipped) {
+            unsafe { self.deallocate_frame(frame) };
+        }
+        self.next = index + count;
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(addr) = self.free_4kib.pop() {
+            return Some(PhysFrame::containing_address(addr));
+        }
+
+        if let Some(frame) = self.unused_4kib_frames().nth(self.next) {
+            self.next += 1;
+            return Some(frame);
+        }
+
+        // out of small frames, break up a large one
+        let addr = match self.free_2mib.pop() {
+            Some(addr) => addr,
+            None => {
+                let addr = self.free_1gib.pop()?;
+                unsafe { self.free_range(addr + Size2MiB::SIZE, addr + Size1GiB::SIZE) };
+                addr
+            }
+        };
+        unsafe { self.free_range(addr + Size4KiB::SIZE, addr + Size2MiB::SIZE) };
+        Some(PhysFrame::containing_address(addr))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.free_4kib.push(frame.start_address());
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate_aligned()
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.free_2mib.push(frame.start_address());
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        self.allocate_aligned()
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        self.free_1gib.push(frame.start_address());
+    }
+}
+
+/// Active Level 4 table.
+fn get_active_level_4_table(physical_offset: VirtAddr) -> &'static mut PageTable {
+    let (frame, _) = x86_64::registers::control::Cr3::read();
+
+    let start = frame.start_address();
+    let virt_start = physical_offset + start.as_u64();
+
+    // this is the place where the page table exists
+    unsafe { &mut *virt_start.as_mut_ptr::<PageTable>() }
+}
+
+pub fn init(physical_offset: VirtAddr, memory_map: &'static MemoryMap) {
+    PHYSICAL_MEMORY_OFFSET.init_once(|| physical_offset);
+    KERNEL_LEVEL_4_FRAME.init_once(|| x86_64::registers::control::Cr3::read().0);
+    MEMORY_MAP.init_once(|| memory_map);
+
+    KERNEL_PAGE_TABLE.init_once(|| {
+        let table = get_active_level_4_table(physical_offset);
+        Mutex::new(unsafe { OffsetPageTable::new(table, physical_offset) })
+    });
+    FRAME_ALLOCATOR.init_once(|| Mutex::new(BootInfoFrameAllocator::init(memory_map)));
+}
+
+pub fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET
+        .try_get()
+        .expect("memory not initialized")
+}
+
+pub fn kernel_level_4_frame() -> PhysFrame {
+    *KERNEL_LEVEL_4_FRAME
+        .try_get()
+        .expect("memory not initialized")
+}
+
+pub fn memory_map() -> &'static MemoryMap {
+    MEMORY_MAP.try_get().expect("memory not initialized")
+}
+
+/// The kernel's own page table.
+///
+/// When both are needed, lock this before the frame allocator.
+pub fn kernel_page_table() -> MutexGuard<'static, OffsetPageTable<'static>> {
+    KERNEL_PAGE_TABLE
+        .try_get()
+        .expect("memory not initialized")
+        .lock()
+}
+
+pub fn frame_allocator() -> MutexGuard<'static, BootInfoFrameAllocator> {
+    FRAME_ALLOCATOR
+        .try_get()
+        .expect("memory not initialized")
+        .lock()
+}
+
+/// Where a physical address can be accessed through the physical memory
+/// mapping.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+/// Allocates a frame and fills it with zeros.
+pub fn allocate_zeroed_frame() -> Option<PhysFrame> {
+    let frame = frame_allocator().allocate_frame()?;
+    unsafe {
+        core::ptr::write_bytes(
+            phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+            0,
+            frame.size() as usize,
+        );
+    }
+    Some(frame)
+}
+
+/// Walks the active page table and checks whether `addr` is mapped
+/// and accessible from ring 3 (and writable, if `write` is set).
+pub fn user_accessible(addr: VirtAddr, write: bool) -> bool {
+    let physical_offset = physical_memory_offset();
+    let mut table: &PageTable = get_active_level_4_table(physical_offset);
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+
+    for (level, &index) in indexes.iter().enumerate() {
+        let flags = table[index].flags();
+        if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE) {
+            return false;
+        }
+        // a copy-on-write page becomes writable when written to
+        let last = level == indexes.len() - 1 || flags.contains(PageTableFlags::HUGE_PAGE);
+        if write && !flags.contains(PageTableFlags::WRITABLE) && !(last && flags.contains(cow::COW))
+        {
+            return false;
+        }
+        if last {
+            break;
+        }
+        let next = physical_offset + table[index].addr().as_u64();
+        table = unsafe { &*next.as_ptr::<PageTable>() };
+    }
+    true
+}
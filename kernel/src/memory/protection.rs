@@ -0,0 +1,126 @@
+//! Hardware memory protection: NX, write protection, SMEP and SMAP, and
+//! page permissions for the kernel image that match its sections.
+
+use crate::{
+    cpu,
+    elf::{ElfFile, FileHeader, PF_W, PF_X},
+    memory,
+};
+use core::{
+    mem::size_of,
+    slice,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{Mapper, Page, PageTable, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+extern "C" {
+    /// Defined by the linker: the ELF header of the kernel, which the
+    /// bootloader loaded as part of the first segment.
+    static __ehdr_start: u8;
+}
+
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether `stac`/`clac` must bracket accesses to user memory.
+pub fn smap_enabled() -> bool {
+    SMAP_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Turns on every protection the CPU supports and tightens the
+/// bootloader's mappings of the kernel.
+///
+/// Must run after `memory::init` and before any address space is created,
+/// since address spaces copy the kernel's level 4 entries.
+pub fn init() {
+    let features = cpu::features();
+    unsafe {
+        if features.nx {
+            Efer::update(|efer| efer.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+        // the kernel must not write to read-only pages either
+        Cr0::update(|cr0| cr0.insert(Cr0Flags::WRITE_PROTECT));
+        // never run user code with kernel privileges
+        if features.smep {
+            Cr4::update(|cr4| cr4.insert(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION));
+        }
+        // never touch user memory outside of `usermode::copy_from_user` and
+        // `usermode::copy_to_user`
+        if features.smap {
+            Cr4::update(|cr4| cr4.insert(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION));
+            SMAP_ENABLED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    if features.nx {
+        remap_kernel_image();
+        protect_physical_memory_mapping();
+    }
+}
+
+/// Maps every segment of the kernel with exactly the permissions its
+/// program header asks for: .text R-X, .rodata R--, .data and .bss RW-.
+fn remap_kernel_image() {
+    let elf = match kernel_elf() {
+        Some(elf) => elf,
+        None => {
+            crate::println!("warning: kernel ELF header not mapped, keeping its page flags");
+            return;
+        }
+    };
+
+    let mut page_table = memory::kernel_page_table();
+    for segment in elf.load_segments() {
+        if segment.memsz == 0 {
+            continue;
+        }
+        let mut flags = PageTableFlags::PRESENT;
+        if segment.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if segment.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+
+        let start = VirtAddr::new(segment.vaddr);
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::<Size4KiB>::containing_address(start + (segment.memsz - 1));
+        for page in Page::range_inclusive(first, last) {
+            unsafe {
+                page_table
+                    .update_flags(page, flags)
+                    .expect("kernel page not mapped")
+                    .flush();
+            }
+        }
+    }
+}
+
+/// The kernel's own ELF and program headers, as loaded by the bootloader.
+fn kernel_elf() -> Option<ElfFile<'static>> {
+    let start = unsafe { &__ehdr_start as *const u8 };
+    let header = unsafe { slice::from_raw_parts(start, size_of::<FileHeader>()) };
+    let header = ElfFile::parse_header(header).ok()?.header;
+    let len = header.phoff as usize + header.phnum as usize * header.phentsize as usize;
+    ElfFile::parse_header(unsafe { slice::from_raw_parts(start, len) }).ok()
+}
+
+/// Nothing is ever executed through the physical memory mapping. It has a
+/// level 4 entry of its own, so NX on that entry covers all of it.
+fn protect_physical_memory_mapping() {
+    let index = memory::physical_memory_offset().p4_index();
+    let level_4_frame = memory::kernel_level_4_frame();
+    let _page_table = memory::kernel_page_table();
+    let table = unsafe {
+        &mut *memory::phys_to_virt(level_4_frame.start_address()).as_mut_ptr::<PageTable>()
+    };
+    let flags = table[index].flags() | PageTableFlags::NO_EXECUTE;
+    table[index].set_flags(flags);
+    x86_64::instructions::tlb::flush_all();
+}
@@ -0,0 +1,203 @@
+//! Kernel stacks with an unmapped guard page below each of them.
+//!
+//! Stacks are carved out of a dedicated region in fixed-size slots. The first
+//! page of every slot stays unmapped, so running off the end of a stack
+//! faults right away instead of silently overwriting whatever is below.
+
+use crate::memory;
+use alloc::{string::String, vec::Vec};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        Size4KiB,
+    },
+    VirtAddr,
+};
+
+/// Inside the level 4 entry of the kernel image and the heap, so the
+/// stacks are visible in every address space.
+pub const KERNEL_STACKS_START: u64 = 0x_0010_0000_0000;
+
+/// Largest stack a slot can hold, the guard page comes on top.
+pub const MAX_STACK_SIZE: u64 = 256 * 1024;
+
+const SLOT_SIZE: u64 = MAX_STACK_SIZE + 4096;
+const MAX_SLOTS: u64 = 4096;
+
+/// Names of the stacks in use by slot, `None` for free slots.
+static SLOTS: Mutex<Vec<Option<String>>> = Mutex::new(Vec::new());
+
+/// A mapped kernel stack. Unmapped and freed on drop.
+pub struct KernelStack {
+    slot: u64,
+    /// Lowest mapped address.
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+impl KernelStack {
+    /// Maps a stack of `size` bytes, rounded up to whole pages.
+    pub fn new(name: impl Into<String>, size: u64) -> Result<Self, MapToError<Size4KiB>> {
+        let size = (size + 4095) & !4095;
+        assert!(0 < size && size <= MAX_STACK_SIZE, "bad stack size");
+
+        let slot = {
+            let mut slots = SLOTS.lock();
+            let slot = match slots.iter().position(Option::is_none) {
+                Some(slot) => slot,
+                None if (slots.len() as u64) < MAX_SLOTS => {
+                    slots.push(None);
+                    slots.len() - 1
+                }
+                None => return Err(MapToError::FrameAllocationFailed),
+            };
+            slots[slot] = Some(name.into());
+            slot as u64
+        };
+
+        let top = VirtAddr::new(slot_start(slot) + SLOT_SIZE);
+        let stack = KernelStack {
+            slot,
+            bottom: top - size,
+            top,
+        };
+
+        if let Err(error) = stack.map() {
+            // unmaps and frees the pages mapped so far, and the slot
+            drop(stack);
+            return Err(error);
+        }
+        Ok(stack)
+    }
+
+    /// Maps every page of the stack to a new frame.
+    fn map(&self) -> Result<(), MapToError<Size4KiB>> {
+        let mut page_table = memory::kernel_page_table();
+        let mut frame_allocator = memory::frame_allocator();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        for page in self.pages() {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let result = unsafe { page_table.map_to(page, frame, flags, &mut *frame_allocator) };
+            match result {
+                Ok(flush) => flush.flush(),
+                Err(error) => {
+                    // not mapped, so `drop` won't find it
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the name overflows of this stack are reported with.
+    pub fn rename(&self, name: impl Into<String>) {
+        SLOTS.lock()[self.slot as usize] = Some(name.into());
+    }
+
+    /// Where the stack pointer starts out.
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        Page::range(
+            Page::containing_address(self.bottom),
+            Page::containing_address(self.top),
+        )
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let mut page_table = memory::kernel_page_table();
+        let mut frame_allocator = memory::frame_allocator();
+        for page in self.pages() {
+            if let Ok((frame, flush)) = page_table.unmap(page) {
+                flush.flush();
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+        }
+        drop(frame_allocator);
+        drop(page_table);
+
+        SLOTS.lock()[self.slot as usize] = None;
+    }
+}
+
+fn slot_start(slot: u64) -> u64 {
+    KERNEL_STACKS_START + slot * SLOT_SIZE
+}
+
+/// Guard pages of stacks we didn't allocate, e.g. the boot stack.
+static GUARDS: Mutex<Vec<(Page, &'static str)>> = Mutex::new(Vec::new());
+
+/// Remembers a stack set up by somebody else, whose guard page is `guard`.
+pub fn register_guard_page(name: &'static str, guard: Page) {
+    GUARDS.lock().push((guard, name));
+}
+
+/// Finds the guard page below the stack we are running on and registers it
+/// as `name`. The bootloader leaves one unmapped page below its stack.
+pub fn register_current_stack(name: &'static str) {
+    let (bottom, _) = current_stack_bounds();
+    register_guard_page(name, Page::containing_address(bottom - 1u64));
+}
+
+/// The mapped area around the stack pointer, for stacks set up by somebody
+/// else. All of it can be read without faulting.
+pub fn current_stack_bounds() -> (VirtAddr, VirtAddr) {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+    let page_table = memory::kernel_page_table();
+    let current = Page::<Size4KiB>::containing_address(VirtAddr::new(rsp));
+    let mut bottom = current;
+    while page_table.translate_page(bottom - 1).is_ok() {
+        bottom -= 1;
+    }
+    let mut top = current;
+    while page_table.translate_page(top).is_ok() {
+        top += 1;
+    }
+    (bottom.start_address(), top.start_address())
+}
+
+/// If `addr` hits the guard page of a known stack, calls `f` with its name.
+///
+/// Meant for fault handlers, so this never waits for a lock: if it is
+/// held, the stack is reported without a name.
+pub fn with_overflowed_stack(addr: VirtAddr, f: impl FnOnce(&str)) -> bool {
+    let addr = addr.as_u64();
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+
+    // only the stack itself is mapped in a slot, everything below it is
+    // as good as a guard page
+    if addr >= KERNEL_STACKS_START && addr < slot_start(MAX_SLOTS) {
+        let slot = ((addr - KERNEL_STACKS_START) / SLOT_SIZE) as usize;
+        let slots = SLOTS.try_lock();
+        let name = slots
+            .as_ref()
+            .and_then(|slots| slots.get(slot)?.as_deref())
+            .unwrap_or("a kernel stack");
+        f(name);
+        return true;
+    }
+
+    let name = GUARDS
+        .try_lock()
+        .and_then(|guards| guards.iter().find(|(guard, _)| *guard == page).map(|g| g.1));
+    match name {
+        Some(name) => {
+            f(name);
+            true
+        }
+        None => false,
+    }
+}
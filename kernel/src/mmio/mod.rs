@@ -0,0 +1,169 @@
+//! Access to memory mapped devices.
+//!
+//! Device memory is mapped into the kernel on demand, uncached unless asked
+//! otherwise, and accessed through the typed registers in `register`. Legacy
+//! devices in I/O port space get the same treatment with `PortRegister`.
+
+use crate::{dma::CacheMode, memory};
+use core::{marker::PhantomData, mem, ops::Deref, ptr};
+use x86_64::{
+    structures::paging::{mapper::MapToError, PageSize, PageTableFlags, Size2MiB, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+mod register;
+
+pub use register::{
+    Field, PortRegister, ReadOnly, ReadWrite, Readable, Register, RegisterValue, Writable,
+    WriteOnly,
+};
+
+/// Physical memory of a device, mapped into the kernel. Unmapped on drop.
+pub struct MmioRegion {
+    /// What `memory::huge::reserve` gave us.
+    reserved: VirtAddr,
+    /// Where the mapping starts, page aligned.
+    mapping: VirtAddr,
+    mapping_size: u64,
+    /// Where the region starts inside the mapping.
+    start: VirtAddr,
+    size: usize,
+}
+
+impl MmioRegion {
+    /// Maps `size` bytes of device memory at `phys`, uncached.
+    ///
+    /// # Safety
+    ///
+    /// `phys` must be device memory that nobody else maps. RAM would let
+    /// `write` overwrite page tables or the kernel.
+    pub unsafe fn map(phys: PhysAddr, size: usize) -> Result<Self, MapToError<Size4KiB>> {
+        Self::map_with(phys, size, CacheMode::Uncacheable)
+    }
+
+    /// Maps `size` bytes of device memory at `phys` with the given caching,
+    /// e.g. write-combining for a framebuffer.
+    ///
+    /// # Safety
+    ///
+    /// As for `map`.
+    pub unsafe fn map_with(
+        phys: PhysAddr,
+        size: usize,
+        cache: CacheMode,
+    ) -> Result<Self, MapToError<Size4KiB>> {
+        let first = phys.align_down(Size4KiB::SIZE);
+        let end = (phys + size as u64).align_up(Size4KiB::SIZE);
+        let mapping_size = end - first;
+        // keep the offset to a 2 MiB boundary, so large regions can use
+        // huge pages
+        let align = if mapping_size >= Size2MiB::SIZE {
+            Size2MiB::SIZE
+        } else {
+            Size4KiB::SIZE
+        };
+        let reserved = memory::huge::reserve(mapping_size + align, align)
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let mapping = reserved + first.as_u64() % align;
+
+        // on failure, drop unmaps what is mapped so far
+        let region = MmioRegion {
+            reserved,
+            mapping,
+            mapping_size,
+            start: mapping + (phys - first),
+            size,
+        };
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | cache.flags();
+        memory::huge::map_physical_range(mapping, first, mapping_size, flags)?;
+        Ok(region)
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Reads the `T` at byte `offset`.
+    pub fn read<T: RegisterValue>(&self, offset: usize) -> T {
+        assert!(
+            self.contains::<T>(offset),
+            "MMIO read out of range or misaligned"
+        );
+        unsafe { ptr::read_volatile((self.start + offset).as_ptr()) }
+    }
+
+    /// Writes `value` at byte `offset`.
+    pub fn write<T: RegisterValue>(&self, offset: usize, value: T) {
+        assert!(
+            self.contains::<T>(offset),
+            "MMIO write out of range or misaligned"
+        );
+        unsafe { ptr::write_volatile((self.start + offset).as_mut_ptr(), value) }
+    }
+
+    /// Whether a `T` at byte `offset` lies within the region and is
+    /// aligned, as volatile accesses require.
+    fn contains<T>(&self, offset: usize) -> bool {
+        let aligned =
+            (self.start.as_u64() as usize).wrapping_add(offset) % mem::align_of::<T>() == 0;
+        aligned
+            && offset
+                .checked_add(mem::size_of::<T>())
+                .map_or(false, |end| end <= self.size)
+    }
+
+    /// Keeps the region mapped forever.
+    ///
+    /// # Safety
+    ///
+    /// The region must hold a `T`, and nothing else may access it.
+    pub unsafe fn leak<T>(self) -> &'static mut T {
+        assert!(mem::size_of::<T>() <= self.size);
+        let value = &mut *self.start.as_mut_ptr();
+        mem::forget(self);
+        value
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        // the frames belong to the device, only the mapping goes away
+        memory::huge::unmap_range(self.mapping, self.mapping_size, false);
+        memory::huge::unreserve(self.reserved);
+    }
+}
+
+/// A register block of type `T`, usually a `#[repr(C)]` struct of
+/// `Register`s, mapped uncached.
+pub struct Mmio<T> {
+    region: MmioRegion,
+    _registers: PhantomData<T>,
+}
+
+impl<T> Mmio<T> {
+    /// # Safety
+    ///
+    /// A device with the register layout `T` must live at `phys`.
+    pub unsafe fn map(phys: PhysAddr) -> Result<Self, MapToError<Size4KiB>> {
+        Ok(Mmio {
+            region: MmioRegion::map(phys, mem::size_of::<T>())?,
+            _registers: PhantomData,
+        })
+    }
+
+    pub fn region(&self) -> &MmioRegion {
+        &self.region
+    }
+}
+
+impl<T> Deref for Mmio<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.region.start.as_ptr() }
+    }
+}
@@ -0,0 +1,191 @@
+//! Typed device registers, in memory and in I/O port space.
+//!
+//! The access type (`ReadOnly`, `WriteOnly`, `ReadWrite`) decides which
+//! methods a register has, so writing a status register or reading a
+//! command register doesn't compile. `Field`s name the bits of a register.
+
+use core::{cell::UnsafeCell, marker::PhantomData, ops::BitOr, ptr};
+use x86_64::instructions::port::{PortRead, PortReadOnly, PortWrite, PortWriteOnly};
+
+/// Types registers can hold.
+pub trait RegisterValue: Copy + BitOr<Output = Self> {
+    fn to_u64(self) -> u64;
+    fn from_u64(value: u64) -> Self;
+}
+
+macro_rules! register_value {
+    ($($t:ty),*) => {
+        $(impl RegisterValue for $t {
+            fn to_u64(self) -> u64 {
+                self as u64
+            }
+
+            fn from_u64(value: u64) -> Self {
+                value as $t
+            }
+        })*
+    };
+}
+
+register_value!(u8, u16, u32, u64);
+
+pub trait Readable {}
+pub trait Writable {}
+
+pub enum ReadOnly {}
+pub enum WriteOnly {}
+pub enum ReadWrite {}
+
+impl Readable for ReadOnly {}
+impl Readable for ReadWrite {}
+impl Writable for WriteOnly {}
+impl Writable for ReadWrite {}
+
+/// `width` bits starting at bit `shift` of a register holding `T`s.
+#[derive(Debug, Clone, Copy)]
+pub struct Field<T> {
+    shift: u32,
+    width: u32,
+    _value: PhantomData<T>,
+}
+
+impl<T: RegisterValue> Field<T> {
+    pub const fn new(shift: u32, width: u32) -> Self {
+        Field {
+            shift,
+            width,
+            _value: PhantomData,
+        }
+    }
+
+    /// A single bit.
+    pub const fn bit(bit: u32) -> Self {
+        Self::new(bit, 1)
+    }
+
+    fn mask(self) -> u64 {
+        if self.width >= 64 {
+            !0
+        } else {
+            (1 << self.width) - 1
+        }
+    }
+
+    /// `value` moved into place, to be or'ed with other fields.
+    pub fn value(self, value: T) -> T {
+        T::from_u64((value.to_u64() & self.mask()) << self.shift)
+    }
+
+    /// The field's bits in `register`, shifted down.
+    pub fn get(self, register: T) -> T {
+        T::from_u64((register.to_u64() >> self.shift) & self.mask())
+    }
+
+    /// `register` with the field replaced by `value`.
+    pub fn set(self, register: T, value: T) -> T {
+        let cleared = register.to_u64() & !(self.mask() << self.shift);
+        T::from_u64(cleared | self.value(value).to_u64())
+    }
+}
+
+/// A memory mapped register. Lives inside a `#[repr(C)]` register block
+/// that is mapped with `mmio::Mmio`.
+#[repr(transparent)]
+pub struct Register<T, A> {
+    value: UnsafeCell<T>,
+    _access: PhantomData<A>,
+}
+
+// registers are accessed with single volatile loads and stores
+unsafe impl<T: Send, A> Sync for Register<T, A> {}
+
+impl<T: RegisterValue, A: Readable> Register<T, A> {
+    pub fn read(&self) -> T {
+        unsafe { ptr::read_volatile(self.value.get()) }
+    }
+
+    pub fn get(&self, field: Field<T>) -> T {
+        field.get(self.read())
+    }
+
+    /// Whether any bit of `field` is set.
+    pub fn is_set(&self, field: Field<T>) -> bool {
+        self.get(field).to_u64() != 0
+    }
+}
+
+impl<T: RegisterValue, A: Writable> Register<T, A> {
+    pub fn write(&self, value: T) {
+        unsafe { ptr::write_volatile(self.value.get(), value) }
+    }
+}
+
+impl<T: RegisterValue> Register<T, ReadWrite> {
+    /// Reads the register, changes it with `f` and writes it back.
+    pub fn modify(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+
+    /// Changes only the bits of `field`.
+    pub fn set(&self, field: Field<T>, value: T) {
+        self.modify(|register| field.set(register, value));
+    }
+}
+
+/// A register in I/O port space.
+#[derive(Debug)]
+pub struct PortRegister<T, A> {
+    port: u16,
+    _value: PhantomData<(T, A)>,
+}
+
+impl<T, A> PortRegister<T, A> {
+    /// # Safety
+    ///
+    /// Accessing the port must not have side effects beyond what the
+    /// access type and `T` promise, e.g. a `ReadOnly` port must not be
+    /// acknowledged by reading it twice.
+    pub const unsafe fn new(port: u16) -> Self {
+        PortRegister {
+            port,
+            _value: PhantomData,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl<T: RegisterValue + PortRead, A: Readable> PortRegister<T, A> {
+    pub fn read(&self) -> T {
+        unsafe { PortReadOnly::new(self.port).read() }
+    }
+
+    pub fn get(&self, field: Field<T>) -> T {
+        field.get(self.read())
+    }
+
+    /// Whether any bit of `field` is set.
+    pub fn is_set(&self, field: Field<T>) -> bool {
+        self.get(field).to_u64() != 0
+    }
+}
+
+impl<T: RegisterValue + PortWrite, A: Writable> PortRegister<T, A> {
+    pub fn write(&self, value: T) {
+        unsafe { PortWriteOnly::new(self.port).write(value) }
+    }
+}
+
+impl<T: RegisterValue + PortRead + PortWrite> PortRegister<T, ReadWrite> {
+    /// Reads the register, changes it with `f` and writes it back.
+    pub fn modify(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+
+    /// Changes only the bits of `field`.
+    pub fn set(&self, field: Field<T>, value: T) {
+        self.modify(|register| field.set(register, value));
+    }
+}
@@ -0,0 +1,328 @@
+//! Processes: user programs with their own address space, a parent
+//! and an exit status that the parent collects with `wait`.
+
+use crate::{
+    fpu::FpuState,
+    ktask::accounting,
+    loader::{self, LoadError},
+    memory::AddressSpace,
+    thread,
+    usermode::{self, UserExit},
+};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Poll, Waker},
+};
+use futures_util::future::poll_fn;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// For pids coming from user programs. Whether the process exists
+    /// is up to whoever looks it up.
+    pub(crate) fn from_u64(pid: u64) -> Self {
+        Pid(pid)
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The process called `exit` with this code.
+    Exited(i32),
+    /// The process was killed after an exception.
+    Killed(&'static str),
+}
+
+impl From<UserExit> for ExitStatus {
+    fn from(exit: UserExit) -> Self {
+        match exit {
+            UserExit::Exited(code) => ExitStatus::Exited(code),
+            UserExit::Killed { exception, .. } => ExitStatus::Killed(exception),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    /// Loaded, but never ran.
+    Runnable,
+    Running,
+    /// Exited, all resources are gone, only the exit status is left
+    /// until the parent waits for it.
+    Zombie(ExitStatus),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    NoSuchProcess,
+    /// Only the parent may wait for a process.
+    NotChild,
+    /// The process is not in a state that allows this operation.
+    InvalidState(ProcessState),
+    Load(LoadError),
+}
+
+impl From<LoadError> for ProcessError {
+    fn from(error: LoadError) -> Self {
+        ProcessError::Load(error)
+    }
+}
+
+struct Process {
+    /// `None` for processes started by the kernel.
+    parent: Option<Pid>,
+    children: Vec<Pid>,
+    /// Set once the parent exited: nobody will wait for this process
+    /// anymore, so it is reaped right away when it exits.
+    orphan: bool,
+    /// Dropped on exit, which gives back every frame the process owned.
+    address_space: Option<AddressSpace>,
+    entry: VirtAddr,
+    stack_pointer: VirtAddr,
+    state: ProcessState,
+    /// Tasks waiting for this process to exit.
+    waiters: Vec<Waker>,
+}
+
+lazy_static! {
+    static ref PROCESSES: Mutex<BTreeMap<Pid, Process>> = Mutex::new(BTreeMap::new());
+}
+
+/// The process the current thread runs.
+pub fn current() -> Option<Pid> {
+    thread::current_process()
+}
+
+/// Loads `image` into a new process. The process doesn't run
+/// until `run` is called.
+pub fn spawn(
+    image: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+    parent: Option<Pid>,
+) -> Result<Pid, ProcessError> {
+    let program = loader::load(image, argv, envp)?;
+    let pid = Pid::new();
+
+    let mut processes = PROCESSES.lock();
+    if let Some(parent) = parent {
+        let parent = processes
+            .get_mut(&parent)
+            .ok_or(ProcessError::NoSuchProcess)?;
+        if let ProcessState::Zombie(_) = parent.state {
+            return Err(ProcessError::InvalidState(parent.state));
+        }
+        parent.children.push(pid);
+    }
+
+    processes.insert(
+        pid,
+        Process {
+            parent,
+            children: Vec::new(),
+            orphan: false,
+            address_space: Some(program.address_space),
+            entry: program.entry,
+            stack_pointer: program.stack_pointer,
+            state: ProcessState::Runnable,
+            waiters: Vec::new(),
+        },
+    );
+    Ok(pid)
+}
+
+/// Runs a freshly spawned process on the current thread until
+/// it exits or is killed, then tears it down.
+pub fn run(pid: Pid) -> Result<ExitStatus, ProcessError> {
+    let (entry, stack_pointer) = {
+        let mut processes = PROCESSES.lock();
+        let process = processes.get_mut(&pid).ok_or(ProcessError::NoSuchProcess)?;
+        if process.state != ProcessState::Runnable {
+            return Err(ProcessError::InvalidState(process.state));
+        }
+        process.state = ProcessState::Running;
+        let address_space = process
+            .address_space
+            .as_ref()
+            .expect("runnable process without memory");
+        // only `exit` drops the address space of a running process, and
+        // only `run` calls it, after we returned from user mode.
+        unsafe { address_space.activate() };
+        (process.entry, process.stack_pointer)
+    };
+
+    // the thread may have run another program before
+    thread::replace_fpu(FpuState::new());
+    thread::set_current_process(Some(pid));
+    // the process isn't part of whatever task runs it
+    let charging = accounting::suspend();
+    let user_exit = unsafe { usermode::enter_usermode(entry, stack_pointer) };
+    drop(charging);
+    thread::set_current_process(None);
+    thread::replace_fpu(FpuState::empty());
+    AddressSpace::activate_kernel();
+
+    let status = ExitStatus::from(user_exit);
+    exit(pid, status);
+    Ok(status)
+}
+
+/// Turns `pid` into a zombie, see `teardown`.
+///
+/// Only `run` calls this, once the process left user mode for good: the
+/// address space must not be loaded in CR3 anywhere when it is dropped.
+fn exit(pid: Pid, status: ExitStatus) {
+    let mut processes = PROCESSES.lock();
+    match processes.get(&pid) {
+        Some(process) if process.state == ProcessState::Running => {}
+        _ => return,
+    }
+    let waiters = teardown(&mut processes, pid, status);
+    drop(processes);
+
+    for waker in waiters {
+        waker.wake();
+    }
+}
+
+/// Tears down a process that was spawned but never `run`, e.g. because
+/// whoever spawned it changed their mind. Its parent collects the exit
+/// status like for any other process.
+pub fn kill(pid: Pid) -> Result<(), ProcessError> {
+    let mut processes = PROCESSES.lock();
+    let process = processes.get(&pid).ok_or(ProcessError::NoSuchProcess)?;
+    // running ones have their address space loaded on some thread
+    if process.state != ProcessState::Runnable {
+        return Err(ProcessError::InvalidState(process.state));
+    }
+    let waiters = teardown(&mut processes, pid, ExitStatus::Killed("killed"));
+    drop(processes);
+
+    for waker in waiters {
+        waker.wake();
+    }
+    Ok(())
+}
+
+/// Turns `pid` into a zombie: frees its address space and hands its
+/// children over to the kernel. Returns the wakers of everybody waiting for
+/// it, to be woken once the table is unlocked.
+fn teardown(processes: &mut BTreeMap<Pid, Process>, pid: Pid, status: ExitStatus) -> Vec<Waker> {
+    let process = processes
+        .get_mut(&pid)
+        .expect("tearing down unknown process");
+    process.state = ProcessState::Zombie(status);
+    drop(process.address_space.take());
+    let children = core::mem::replace(&mut process.children, Vec::new());
+    let waiters = core::mem::replace(&mut process.waiters, Vec::new());
+    let orphan = process.orphan;
+
+    for child in children {
+        let child_process = processes.get_mut(&child).expect("child vanished");
+        child_process.parent = None;
+        child_process.orphan = true;
+        if let ProcessState::Zombie(_) = child_process.state {
+            reap(processes, child);
+        }
+    }
+    if orphan {
+        reap(processes, pid);
+    }
+    waiters
+}
+
+/// Collects the exit status of `pid` if it already exited.
+/// Only the parent of `pid` may do this.
+pub fn try_wait(pid: Pid) -> Result<Option<ExitStatus>, ProcessError> {
+    let caller = current();
+    let mut processes = PROCESSES.lock();
+    let process = processes.get(&pid).ok_or(ProcessError::NoSuchProcess)?;
+    check_parent(process, caller)?;
+    match process.state {
+        ProcessState::Zombie(status) => {
+            reap(&mut processes, pid);
+            Ok(Some(status))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Waits until `pid` exits and collects its exit status.
+/// Only the parent of `pid` may do this: the process of the thread that
+/// first polls the future, as for `SYS_WAIT`.
+pub async fn wait(pid: Pid) -> Result<ExitStatus, ProcessError> {
+    let caller = current();
+    poll_fn(|cx| {
+        let mut processes = PROCESSES.lock();
+        let process = match processes.get_mut(&pid) {
+            Some(process) => process,
+            None => return Poll::Ready(Err(ProcessError::NoSuchProcess)),
+        };
+        if let Err(error) = check_parent(process, caller) {
+            return Poll::Ready(Err(error));
+        }
+        match process.state {
+            ProcessState::Zombie(status) => {
+                reap(&mut processes, pid);
+                Poll::Ready(Ok(status))
+            }
+            _ => {
+                if !process.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                    process.waiters.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+    })
+    .await
+}
+
+pub fn state(pid: Pid) -> Option<ProcessState> {
+    PROCESSES.lock().get(&pid).map(|process| process.state)
+}
+
+pub fn parent(pid: Pid) -> Option<Pid> {
+    PROCESSES
+        .lock()
+        .get(&pid)
+        .and_then(|process| process.parent)
+}
+
+/// Processes started by the kernel belong to callers outside any process.
+/// Orphans belong to nobody: they reap themselves.
+fn check_parent(process: &Process, caller: Option<Pid>) -> Result<(), ProcessError> {
+    if process.orphan || process.parent != caller {
+        return Err(ProcessError::NotChild);
+    }
+    Ok(())
+}
+
+/// Removes the zombie `pid` from the process table.
+fn reap(processes: &mut BTreeMap<Pid, Process>, pid: Pid) {
+    let process = processes.remove(&pid).expect("reaping unknown process");
+    debug_assert!(process.address_space.is_none());
+    if let Some(parent) = process.parent.and_then(|parent| processes.get_mut(&parent)) {
+        parent.children.retain(|&child| child != pid);
+    }
+}
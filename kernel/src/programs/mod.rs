@@ -0,0 +1,5 @@
+//! User programs embedded into the kernel image until we have a file system.
+//! The build script assembles them from the sources in this directory.
+
+/// Prints a greeting and its `argv[0]`, then exits with `argc`.
+pub static HELLO: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/hello.elf"));
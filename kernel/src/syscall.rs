@@ -0,0 +1,186 @@
+//! System calls issued by user programs through `int 0x80`.
+//!
+//! The system call number is passed in `rax` and the arguments in
+//! `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`. The result is returned
+//! in `rax`, negative values are errors.
+
+use crate::{
+    ktask::{self, accounting},
+    process::{self, ExitStatus, Pid},
+    usermode::{self, UserExit},
+};
+use x86_64::{instructions::interrupts, structures::idt::HandlerFunc, VirtAddr};
+
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_GETPID: u64 = 2;
+pub const SYS_WAIT: u64 = 3;
+
+pub const EBADF: i64 = -9;
+pub const ECHILD: i64 = -10;
+pub const EFAULT: i64 = -14;
+pub const ENOSYS: i64 = -38;
+
+/// What `SYS_WAIT` stores for a child that was killed instead of exiting.
+pub const WAIT_KILLED: i64 = i64::MIN;
+
+// Saves every general purpose register so that the dispatcher can read the
+// arguments and write the result, then returns with `iretq`. The CPU aligns
+// the stack to 16 bytes before pushing the 5-word interrupt frame, so after
+// pushing 15 registers the stack is aligned again for the call.
+global_asm!(
+    r#"
+.intel_syntax noprefix
+.global __syscall_entry
+__syscall_entry:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    cld
+    call syscall_dispatch
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    iretq
+.att_syntax
+"#
+);
+
+extern "C" {
+    fn __syscall_entry();
+}
+
+/// Registers saved by `__syscall_entry`, followed by the interrupt frame.
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl SyscallFrame {
+    fn from_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+/// The IDT only takes `x86-interrupt` functions, but the entry is
+/// plain assembly that does its own `iretq`.
+pub(crate) fn handler() -> HandlerFunc {
+    unsafe { core::mem::transmute(__syscall_entry as unsafe extern "C" fn()) }
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    // before anything else: the caller may have set AC to disable SMAP
+    usermode::clear_user_access();
+    let _charging = accounting::suspend();
+    let result = match frame.rax {
+        SYS_EXIT => sys_exit(frame, frame.rdi as i32),
+        SYS_WRITE => sys_write(frame.rdi, frame.rsi, frame.rdx as usize),
+        SYS_GETPID => sys_getpid(),
+        SYS_WAIT => sys_wait(frame.rdi, frame.rsi),
+        _ => ENOSYS,
+    };
+    frame.rax = result as u64;
+}
+
+fn sys_exit(frame: &SyscallFrame, code: i32) -> i64 {
+    if !frame.from_user() {
+        return ENOSYS;
+    }
+    usermode::exit_to_kernel(UserExit::Exited(code))
+}
+
+fn sys_write(fd: u64, buf: u64, len: usize) -> i64 {
+    if fd != 1 && fd != 2 {
+        return EBADF;
+    }
+
+    let mut chunk = [0u8; 256];
+    let mut written = 0;
+    while written < len {
+        let n = (len - written).min(chunk.len());
+        let src = match VirtAddr::try_new(buf.wrapping_add(written as u64)) {
+            Ok(src) => src,
+            Err(_) => return EFAULT,
+        };
+        if usermode::copy_from_user(&mut chunk[..n], src).is_err() {
+            return EFAULT;
+        }
+        crate::vga::print_bytes(&chunk[..n]);
+        written += n;
+    }
+    written as i64
+}
+
+fn sys_getpid() -> i64 {
+    process::current().map_or(0, |pid| pid.as_u64() as i64)
+}
+
+/// Blocks until the child `pid` exits and reaps it. Its exit code, or
+/// `WAIT_KILLED`, is stored as an `i64` at `status` unless that is null.
+fn sys_wait(pid: u64, status: u64) -> i64 {
+    // the child runs on another thread, which needs the timer to get the CPU
+    interrupts::enable();
+    let result = ktask::block_on(process::wait(Pid::from_u64(pid)));
+    interrupts::disable();
+    let code = match result {
+        Ok(ExitStatus::Exited(code)) => code as i64,
+        Ok(ExitStatus::Killed(_)) => WAIT_KILLED,
+        Err(_) => return ECHILD,
+    };
+
+    if status != 0 {
+        let copied = VirtAddr::try_new(status)
+            .map_err(|_| ())
+            .and_then(|status| usermode::copy_to_user(status, &code.to_ne_bytes()));
+        if copied.is_err() {
+            return EFAULT;
+        }
+    }
+    0
+}
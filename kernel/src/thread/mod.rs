@@ -0,0 +1,122 @@
+//! Preemptive kernel threads.
+//!
+//! Every thread has its own kernel stack and is preempted by the timer
+//! after `scheduler::TIME_SLICE_TICKS` ticks, so a thread that never
+//! yields can't freeze the machine.
+
+use crate::{fpu::FpuState, process::Pid, time};
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use spin::Mutex;
+
+pub(crate) mod scheduler;
+
+pub use scheduler::{ThreadState, THREAD_STACK_SIZE, TIME_SLICE_TICKS};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Owned permission to wait for a thread and take its result.
+/// Dropping it detaches the thread.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Blocks until the thread finished and returns what it returned.
+    pub fn join(self) -> T {
+        scheduler::join(self.id);
+        self.result
+            .lock()
+            .take()
+            .expect("thread exited without a result")
+    }
+}
+
+/// Turns the boot code into the first thread. Must run once the heap works.
+pub fn init() {
+    scheduler::init();
+}
+
+/// Starts a new thread running `f`.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let slot = result.clone();
+    let id = scheduler::spawn(Box::new(move || {
+        let value = f();
+        *slot.lock() = Some(value);
+    }));
+    JoinHandle { id, result }
+}
+
+pub fn current() -> ThreadId {
+    scheduler::current()
+}
+
+/// `None` once the thread exited and was cleaned up.
+pub fn state(id: ThreadId) -> Option<ThreadState> {
+    scheduler::state(id)
+}
+
+/// Lets other ready threads run before the current one continues.
+pub fn yield_now() {
+    scheduler::yield_now();
+}
+
+/// Blocks the current thread for at least `duration`.
+pub fn sleep(duration: Duration) {
+    scheduler::sleep_until(time::ticks() + time::duration_to_ticks(duration));
+}
+
+/// Ends the current thread without producing a result; joining
+/// it panics.
+pub fn exit() -> ! {
+    scheduler::exit()
+}
+
+/// The process whose user code the current thread runs, if any.
+pub fn current_process() -> Option<Pid> {
+    scheduler::with_current(|thread| thread.process)
+}
+
+pub(crate) fn set_current_process(pid: Option<Pid>) {
+    scheduler::with_current(|thread| thread.process = pid);
+}
+
+/// Gives the current thread `state` as the registers of its user code.
+/// The old state is freed, and nothing of it stays in the registers.
+pub(crate) fn replace_fpu(state: FpuState) {
+    let old = scheduler::with_current(|thread| thread.fpu.replace(state));
+    // freed without the scheduler locked
+    drop(old);
+}
@@ -0,0 +1,462 @@
+//! Round-robin scheduling of kernel threads.
+//!
+//! The scheduler is only ever locked with interrupts disabled, so the timer
+//! interrupt can't find it locked by the thread it preempted. Switching
+//! threads happens with interrupts disabled as well; each thread restores its
+//! own interrupt flag when it resumes, either by returning from the interrupt
+//! handler it was preempted in or from `without_interrupts`.
+
+use crate::{
+    fpu::{self, FpuState},
+    gdt,
+    ktask::TaskContext,
+    memory::stack::{self, KernelStack},
+    process::Pid,
+    time,
+    usermode::UserContext,
+};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    format,
+    vec::Vec,
+};
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, MutexGuard};
+use x86_64::{instructions::interrupts, registers::control::Cr3, structures::paging::PhysFrame};
+
+use super::ThreadId;
+
+/// Size of the kernel stack of every thread but the boot thread.
+pub const THREAD_STACK_SIZE: u64 = 16 * 1024;
+
+/// Timer ticks a thread may run before it is preempted.
+pub const TIME_SLICE_TICKS: u64 = 2;
+
+/// Stacks of exited threads kept for new ones, the rest are unmapped.
+const MAX_SPARE_STACKS: usize = 4;
+
+// `__switch_context` saves the callee-saved registers and RFLAGS (for AC, see
+// `usermode::UserAccess`) on the current stack, stores the stack pointer in
+// `*old_rsp` and resumes the thread whose saved stack pointer is `new_rsp`.
+// Everything else was already saved by the caller (or by the interrupt
+// handler that preempted it), as per the calling convention.
+//
+// A new thread starts in `__thread_entry`, with the pointer to its main
+// function in r12.
+global_asm!(
+    r#"
+.intel_syntax noprefix
+.global __switch_context
+__switch_context:
+    pushfq
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    popfq
+    ret
+
+.global __thread_entry
+__thread_entry:
+    mov rdi, r12
+    xor ebp, ebp
+    and rsp, -16
+    call thread_start
+    ud2
+.att_syntax
+"#
+);
+
+extern "C" {
+    fn __switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn __thread_entry();
+}
+
+pub(crate) type ThreadMain = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    /// Waiting for the tick count to reach this value.
+    Sleeping(u64),
+    /// Waiting for another thread to exit.
+    Joining(ThreadId),
+    Exited,
+}
+
+pub(crate) struct Thread {
+    state: ThreadState,
+    /// Saved stack pointer while the thread is not running.
+    rsp: u64,
+    /// `None` for the boot thread, which runs on the bootloader's stack.
+    stack: Option<KernelStack>,
+    /// The page table the thread was running with when it was switched out.
+    page_table: PhysFrame,
+    pub(crate) user: UserContext,
+    pub(crate) process: Option<Pid>,
+    /// Registers of user code, saved lazily, see `fpu`. Empty unless the
+    /// thread runs a process.
+    pub(crate) fpu: FpuState,
+    /// What `ktask` knows about the thread while it is switched out.
+    task: TaskContext,
+}
+
+impl Thread {
+    fn new(state: ThreadState, stack: Option<KernelStack>) -> Box<Self> {
+        Box::new(Thread {
+            state,
+            rsp: 0,
+            stack,
+            page_table: Cr3::read().0,
+            user: UserContext::new(),
+            process: None,
+            fpu: FpuState::empty(),
+            task: TaskContext::default(),
+        })
+    }
+}
+
+struct Scheduler {
+    /// Boxed so that `rsp` and `user` stay put while the map changes.
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+
+    /// Threads waiting for the CPU. It always has room for every thread,
+    /// so the timer interrupt never allocates when it requeues one.
+    ready: VecDeque<ThreadId>,
+
+    current: ThreadId,
+
+    /// Runs when nobody else can.
+    idle: Option<ThreadId>,
+
+    /// Ticks left before the current thread is preempted.
+    slice_left: u64,
+
+    /// The bootloader's stack the boot thread runs on.
+    boot_stack: (u64, u64),
+
+    /// Stacks of exited threads, up to `MAX_SPARE_STACKS`, kept for new
+    /// threads instead of being unmapped. See `reap_exited`.
+    spare_stacks: Vec<KernelStack>,
+}
+
+static SCHEDULER: OnceCell<Mutex<Scheduler>> = OnceCell::uninit();
+
+/// Bounds of the stack of the running thread, for walking it without locks.
+static STACK_BOTTOM: AtomicU64 = AtomicU64::new(0);
+static STACK_TOP: AtomicU64 = AtomicU64::new(0);
+
+/// The id of the running thread, for reading it without locks. Whatever
+/// runs before `init` becomes the boot thread, the first one.
+static RUNNING: AtomicU64 = AtomicU64::new(0);
+
+/// Turns the code running right now into the boot thread and starts
+/// the idle thread.
+pub(crate) fn init() {
+    let boot = ThreadId::new();
+    RUNNING.store(boot.0, Ordering::Relaxed);
+    let (bottom, top) = stack::current_stack_bounds();
+    let boot_stack = (bottom.as_u64(), top.as_u64());
+    set_stack_bounds(boot_stack);
+    SCHEDULER.init_once(|| {
+        let mut threads = BTreeMap::new();
+        threads.insert(boot, Thread::new(ThreadState::Running, None));
+        Mutex::new(Scheduler {
+            threads,
+            ready: VecDeque::new(),
+            current: boot,
+            idle: None,
+            slice_left: TIME_SLICE_TICKS,
+            boot_stack,
+            spare_stacks: Vec::new(),
+        })
+    });
+
+    let idle = spawn(Box::new(|| loop {
+        reap_exited();
+        interrupts::enable_interrupts_and_hlt();
+    }));
+    interrupts::without_interrupts(|| {
+        let mut scheduler = lock();
+        scheduler.ready.retain(|&id| id != idle);
+        scheduler.idle = Some(idle);
+    });
+}
+
+/// Must be called with interrupts disabled.
+fn lock() -> MutexGuard<'static, Scheduler> {
+    debug_assert!(!interrupts::are_enabled());
+    SCHEDULER.try_get().expect("threads not initialized").lock()
+}
+
+/// Creates a thread that runs `main` and makes it ready.
+pub(crate) fn spawn(main: ThreadMain) -> ThreadId {
+    reap_exited();
+    let id = ThreadId::new();
+    let name = format!("thread {}", id);
+    let stack = match interrupts::without_interrupts(|| lock().spare_stacks.pop()) {
+        Some(stack) => {
+            stack.rename(name);
+            stack
+        }
+        None => KernelStack::new(name, THREAD_STACK_SIZE).expect("no memory for a thread stack"),
+    };
+
+    // the frame `__switch_context` pops when it first switches to the thread
+    let main = Box::into_raw(Box::new(main));
+    let top = stack.top().as_u64() & !0xf;
+    let frame: [u64; 9] = [
+        0,                              // r15
+        0,                              // r14
+        0,                              // r13
+        main as u64,                    // r12
+        0,                              // rbx
+        0,                              // rbp
+        0x2,                            // rflags, interrupts still disabled
+        __thread_entry as usize as u64, // return address
+        0,
+    ];
+    let mut thread = Thread::new(ThreadState::Ready, Some(stack));
+    thread.rsp = top - core::mem::size_of_val(&frame) as u64;
+    unsafe { core::ptr::write(thread.rsp as *mut [u64; 9], frame) };
+
+    interrupts::without_interrupts(|| {
+        let mut scheduler = lock();
+        scheduler.threads.insert(id, thread);
+        let threads = scheduler.threads.len();
+        scheduler.ready.reserve(threads);
+        scheduler.ready.push_back(id);
+    });
+    id
+}
+
+#[no_mangle]
+extern "C" fn thread_start(main: *mut ThreadMain) -> ! {
+    interrupts::enable();
+
+    let main = unsafe { Box::from_raw(main) };
+    main();
+    exit()
+}
+
+/// The lowest and highest address of the current thread's kernel stack,
+/// both 0 before threads are initialized. Doesn't lock, so it can be used
+/// anywhere.
+pub(crate) fn current_stack_bounds() -> (u64, u64) {
+    (
+        STACK_BOTTOM.load(Ordering::Relaxed),
+        STACK_TOP.load(Ordering::Relaxed),
+    )
+}
+
+fn set_stack_bounds((bottom, top): (u64, u64)) {
+    // empty while the bounds change, for interrupts in between
+    STACK_TOP.store(0, Ordering::Relaxed);
+    STACK_BOTTOM.store(bottom, Ordering::Relaxed);
+    STACK_TOP.store(top, Ordering::Relaxed);
+}
+
+/// Doesn't lock, so even the allocator may ask.
+pub(crate) fn current() -> ThreadId {
+    ThreadId(RUNNING.load(Ordering::Relaxed))
+}
+
+pub(crate) fn state(id: ThreadId) -> Option<ThreadState> {
+    interrupts::without_interrupts(|| lock().threads.get(&id).map(|thread| thread.state))
+}
+
+/// Gives up the rest of the time slice if another thread is ready.
+pub(crate) fn yield_now() {
+    interrupts::without_interrupts(|| {
+        let scheduler = lock();
+        if !scheduler.ready.is_empty() {
+            switch(scheduler);
+        }
+    });
+}
+
+/// Blocks until the tick count reaches `deadline`.
+pub(crate) fn sleep_until(deadline: u64) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = lock();
+        if time::ticks() >= deadline {
+            return;
+        }
+        scheduler.current_mut().state = ThreadState::Sleeping(deadline);
+        switch(scheduler);
+    });
+}
+
+/// Blocks until `id` exited.
+pub(crate) fn join(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = lock();
+        assert_ne!(id, scheduler.current, "a thread can't join itself");
+        match scheduler.threads.get(&id) {
+            Some(thread) if thread.state != ThreadState::Exited => {}
+            _ => return,
+        }
+        scheduler.current_mut().state = ThreadState::Joining(id);
+        switch(scheduler);
+    });
+}
+
+/// Ends the current thread. It is freed by the idle thread or the
+/// next `spawn`.
+pub(crate) fn exit() -> ! {
+    interrupts::disable();
+    let mut scheduler = lock();
+    let current = scheduler.current;
+    assert!(
+        scheduler.threads[&current].stack.is_some(),
+        "the boot thread can't exit"
+    );
+    scheduler.current_mut().state = ThreadState::Exited;
+
+    let Scheduler { threads, ready, .. } = &mut *scheduler;
+    for (&id, thread) in threads.iter_mut() {
+        if thread.state == ThreadState::Joining(current) {
+            thread.state = ThreadState::Ready;
+            ready.push_back(id);
+        }
+    }
+    switch(scheduler);
+    unreachable!("exited thread was resumed");
+}
+
+/// Called from the timer interrupt after the end of interrupt was sent.
+pub(crate) fn timer_tick() {
+    let mut scheduler = match SCHEDULER.try_get() {
+        Ok(scheduler) => scheduler.lock(),
+        Err(_) => return,
+    };
+
+    let now = time::ticks();
+    let Scheduler { threads, ready, .. } = &mut *scheduler;
+    for (&id, thread) in threads.iter_mut() {
+        if let ThreadState::Sleeping(deadline) = thread.state {
+            if deadline <= now {
+                thread.state = ThreadState::Ready;
+                ready.push_back(id);
+            }
+        }
+    }
+
+    scheduler.slice_left = scheduler.slice_left.saturating_sub(1);
+    let idling = Some(scheduler.current) == scheduler.idle;
+    if !scheduler.ready.is_empty() && (scheduler.slice_left == 0 || idling) {
+        switch(scheduler);
+    }
+}
+
+pub(crate) fn with_current<R>(f: impl FnOnce(&mut Thread) -> R) -> R {
+    interrupts::without_interrupts(|| f(lock().current_mut()))
+}
+
+impl Scheduler {
+    fn current_mut(&mut self) -> &mut Thread {
+        let current = self.current;
+        self.threads
+            .get_mut(&current)
+            .expect("current thread vanished")
+    }
+}
+
+/// Switches to the next ready thread. The current thread is requeued
+/// unless it blocked or exited.
+///
+/// Returns when the current thread is scheduled again.
+fn switch(mut scheduler: MutexGuard<Scheduler>) {
+    let current = scheduler.current;
+    let next = match scheduler.ready.pop_front().or(scheduler.idle) {
+        Some(next) => next,
+        None => panic!("no thread left to run"),
+    };
+
+    if scheduler.current_mut().state == ThreadState::Running {
+        if next == current {
+            scheduler.slice_left = TIME_SLICE_TICKS;
+            return;
+        }
+        scheduler.current_mut().state = ThreadState::Ready;
+        if Some(current) != scheduler.idle {
+            scheduler.ready.push_back(current);
+        }
+    }
+
+    let old = scheduler.current_mut();
+    old.page_table = Cr3::read().0;
+    old.task = TaskContext::save();
+    let old_rsp: *mut u64 = &mut old.rsp;
+
+    let boot_stack = scheduler.boot_stack;
+    let new = scheduler
+        .threads
+        .get_mut(&next)
+        .expect("ready thread vanished");
+    new.state = ThreadState::Running;
+    let (page_table, flags) = Cr3::read();
+    if new.page_table != page_table {
+        unsafe { Cr3::write(new.page_table, flags) };
+    }
+    fpu::switch_to(&new.fpu);
+    new.task.restore();
+    set_stack_bounds(new.stack.as_ref().map_or(boot_stack, |stack| {
+        (stack.bottom().as_u64(), stack.top().as_u64())
+    }));
+    if let Some(kernel_stack) = new.user.kernel_stack() {
+        gdt::set_kernel_stack(kernel_stack);
+    }
+    let new_rsp = new.rsp;
+
+    scheduler.current = next;
+    RUNNING.store(next.0, Ordering::Relaxed);
+    scheduler.slice_left = TIME_SLICE_TICKS;
+    drop(scheduler);
+
+    unsafe { __switch_context(old_rsp, new_rsp) };
+}
+
+/// Frees the threads that exited, now that nobody runs on their stacks
+/// anymore. Called by `spawn` and the idle thread, never from an interrupt
+/// handler: dropping a thread frees its FPU area and may unmap its stack,
+/// and `spare_stacks` grows.
+fn reap_exited() {
+    let exited: Vec<Box<Thread>> = interrupts::without_interrupts(|| {
+        let mut scheduler = lock();
+        let current = scheduler.current;
+        let ids: Vec<ThreadId> = scheduler
+            .threads
+            .iter()
+            .filter(|&(&id, thread)| id != current && thread.state == ThreadState::Exited)
+            .map(|(&id, _)| id)
+            .collect();
+        let mut exited = Vec::with_capacity(ids.len());
+        for id in ids {
+            let mut thread = scheduler.threads.remove(&id).unwrap();
+            if scheduler.spare_stacks.len() < MAX_SPARE_STACKS {
+                if let Some(stack) = thread.stack.take() {
+                    scheduler.spare_stacks.push(stack);
+                }
+            }
+            exited.push(thread);
+        }
+        exited
+    });
+    // the rest of each thread, maybe with its stack, is freed without the
+    // scheduler locked
+    drop(exited);
+}
@@ -0,0 +1,85 @@
+//! The system clock, driven by the PIT on IRQ 0.
+
+use crate::mmio::{Field, PortRegister, ReadWrite, WriteOnly};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+pub const TICKS_PER_SECOND: u64 = 100;
+
+/// Input frequency of the PIT in Hz.
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+const PIT_CHANNEL_0: PortRegister<u8, ReadWrite> = unsafe { PortRegister::new(0x40) };
+const PIT_COMMAND: PortRegister<u8, WriteOnly> = unsafe { PortRegister::new(0x43) };
+
+// fields of the command register
+const PIT_CHANNEL: Field<u8> = Field::new(6, 2);
+const PIT_ACCESS: Field<u8> = Field::new(4, 2);
+const PIT_MODE: Field<u8> = Field::new(1, 3);
+
+const ACCESS_LOBYTE_HIBYTE: u8 = 0b11;
+const MODE_RATE_GENERATOR: u8 = 0b010;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The time stamp counter at the first tick, to work out its frequency.
+static TSC_AT_FIRST_TICK: AtomicU64 = AtomicU64::new(0);
+
+/// Programs channel 0 of the PIT to fire `TICKS_PER_SECOND` times a second.
+pub fn init() {
+    let divisor = (PIT_FREQUENCY / TICKS_PER_SECOND) as u16;
+    PIT_COMMAND.write(
+        PIT_CHANNEL.value(0)
+            | PIT_ACCESS.value(ACCESS_LOBYTE_HIBYTE)
+            | PIT_MODE.value(MODE_RATE_GENERATOR),
+    );
+    PIT_CHANNEL_0.write(divisor as u8);
+    PIT_CHANNEL_0.write((divisor >> 8) as u8);
+}
+
+/// Called from the timer interrupt.
+pub(crate) fn tick() {
+    if TICKS.fetch_add(1, Ordering::Relaxed) == 0 {
+        TSC_AT_FIRST_TICK.store(cycles(), Ordering::Relaxed);
+    }
+}
+
+/// Timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+/// How many ticks `duration` takes, rounded up.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos_per_tick = 1_000_000_000 / TICKS_PER_SECOND as u128;
+    ((duration.as_nanos() + nanos_per_tick - 1) / nanos_per_tick) as u64
+}
+
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_millis(ticks * 1000 / TICKS_PER_SECOND)
+}
+
+/// The time stamp counter, for measuring short intervals that ticks are
+/// too coarse for. Convert differences with `cycles_to_duration`.
+pub fn cycles() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// How long `cycles` of the time stamp counter take. The frequency is
+/// measured against the ticks since boot, so this is rough during the first
+/// ticks, and assumes 1 GHz before the second one.
+pub fn cycles_to_duration(cycles: u64) -> Duration {
+    let ticks = ticks().saturating_sub(1);
+    let start = TSC_AT_FIRST_TICK.load(Ordering::Relaxed);
+    if ticks == 0 || start == 0 {
+        return Duration::from_nanos(cycles);
+    }
+    let per_second = (self::cycles() - start) as u128 * TICKS_PER_SECOND as u128 / ticks as u128;
+    Duration::from_nanos((cycles as u128 * 1_000_000_000 / per_second.max(1)) as u64)
+}
@@ -0,0 +1,269 @@
+use crate::{
+    gdt,
+    memory::{self, protection},
+    thread,
+};
+use x86_64::{instructions::interrupts, structures::idt::InterruptStackFrame, VirtAddr};
+
+// `__enter_usermode` saves the callee-saved registers on the current kernel
+// stack, records the resulting stack pointer both as the return point and as
+// RSP0 in the TSS, and then `iretq`s into ring 3.
+//
+// `__return_to_kernel` is the other half: it is jumped to from an interrupt
+// handler running on that same kernel stack, restores the saved registers and
+// returns from `__enter_usermode` as if it were an ordinary function call.
+global_asm!(
+    r#"
+.intel_syntax noprefix
+.global __enter_usermode
+__enter_usermode:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [r8], rsp
+    mov [r9], rsp
+
+    push rcx
+    push rsi
+    push 0x202
+    push rdx
+    push rdi
+
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    xor r11d, r11d
+    xor r12d, r12d
+    xor r13d, r13d
+    xor r14d, r14d
+    xor r15d, r15d
+    iretq
+
+.global __return_to_kernel
+__return_to_kernel:
+    mov rsp, rdi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+.att_syntax
+"#
+);
+
+extern "C" {
+    fn __enter_usermode(
+        entry: u64,
+        stack: u64,
+        code_selector: u64,
+        data_selector: u64,
+        return_rsp: *mut u64,
+        kernel_stack: *mut u64,
+    );
+    fn __return_to_kernel(return_rsp: u64) -> !;
+}
+
+/// Why a program running in ring 3 gave control back to the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserExit {
+    /// The program called the exit system call.
+    Exited(i32),
+    /// The program caused an exception and was killed.
+    Killed {
+        exception: &'static str,
+        ip: VirtAddr,
+    },
+}
+
+/// Per-thread state of the user code a thread runs, if any.
+pub(crate) struct UserContext {
+    /// Kernel stack pointer to restore in `__return_to_kernel`.
+    return_rsp: u64,
+    exit: Option<UserExit>,
+    active: bool,
+}
+
+impl UserContext {
+    pub(crate) const fn new() -> Self {
+        UserContext {
+            return_rsp: 0,
+            exit: None,
+            active: false,
+        }
+    }
+
+    /// The stack the CPU must switch to when user code of this thread
+    /// is interrupted.
+    pub(crate) fn kernel_stack(&self) -> Option<VirtAddr> {
+        if self.active {
+            Some(VirtAddr::new(self.return_rsp))
+        } else {
+            None
+        }
+    }
+}
+
+/// The context of the current thread. It lives as long as the thread,
+/// and is only touched by the thread itself with interrupts disabled.
+fn context() -> *mut UserContext {
+    thread::scheduler::with_current(|thread| &mut thread.user as *mut UserContext)
+}
+
+/// Jumps to `entry` in ring 3 with the stack pointer set to `stack`,
+/// and returns once the program exits or is killed.
+///
+/// Interrupts and exceptions raised by user code are handled on the
+/// kernel stack this function was called on.
+///
+/// # Safety
+///
+/// `entry` and `stack` must be mapped user-accessible in the active
+/// page table, and the caller must not already be running user code.
+pub unsafe fn enter_usermode(entry: VirtAddr, stack: VirtAddr) -> UserExit {
+    let selectors = gdt::selectors();
+    let interrupts_enabled = interrupts::are_enabled();
+
+    interrupts::disable();
+    let context = &mut *context();
+    assert!(!context.active, "already running user code");
+    context.active = true;
+
+    __enter_usermode(
+        entry.as_u64(),
+        stack.as_u64(),
+        selectors.user_code_selector.0 as u64,
+        selectors.user_data_selector.0 as u64,
+        &mut context.return_rsp,
+        gdt::kernel_stack_slot(),
+    );
+
+    // we are back from `__return_to_kernel`, still with interrupts disabled.
+    context.active = false;
+    let exit = context
+        .exit
+        .take()
+        .expect("returned from user mode without a reason");
+    if interrupts_enabled {
+        interrupts::enable();
+    }
+    exit
+}
+
+/// Abandons the running user program and resumes the kernel right after
+/// the `enter_usermode` call that started it.
+///
+/// Must only be called from an interrupt handler entered from ring 3.
+pub(crate) fn exit_to_kernel(exit: UserExit) -> ! {
+    unsafe {
+        let context = &mut *context();
+        assert!(context.active, "not running user code");
+        context.exit = Some(exit);
+        __return_to_kernel(context.return_rsp)
+    }
+}
+
+/// Whether the interrupted code was running in ring 3.
+pub fn is_user_frame(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+/// Kills the interrupted program if the exception was raised in ring 3,
+/// otherwise returns and lets the handler deal with a kernel fault.
+pub(crate) fn kill_on_user_fault(stack_frame: &InterruptStackFrame, exception: &'static str) {
+    if is_user_frame(stack_frame) {
+        exit_to_kernel(UserExit::Killed {
+            exception,
+            ip: stack_frame.instruction_pointer,
+        });
+    }
+}
+
+/// Clears RFLAGS.AC, which user code can set with `popf` to switch SMAP
+/// off for the kernel. Every interrupt, exception and system call handler
+/// calls this first: interrupt and trap gates don't clear the flag, and
+/// `iretq` restores it for whatever code was interrupted.
+#[inline(always)]
+pub(crate) fn clear_user_access() {
+    if protection::smap_enabled() {
+        unsafe { asm!("clac", options(nomem, nostack)) };
+    }
+}
+
+/// Allows the kernel to access user pages while it is alive.
+///
+/// With SMAP enabled any kernel access to a user page faults, unless
+/// RFLAGS.AC is set with `stac`. The flag is saved per thread, so being
+/// preempted while holding this is fine. Only the copy helpers below use
+/// this, after `check_user_range` validated the range.
+struct UserAccess {
+    _private: (),
+}
+
+impl UserAccess {
+    fn begin() -> Self {
+        if protection::smap_enabled() {
+            unsafe { asm!("stac", options(nomem, nostack)) };
+        }
+        UserAccess { _private: () }
+    }
+}
+
+impl Drop for UserAccess {
+    fn drop(&mut self) {
+        if protection::smap_enabled() {
+            unsafe { asm!("clac", options(nomem, nostack)) };
+        }
+    }
+}
+
+/// Copies `dst.len()` bytes from user address `src` into `dst`.
+///
+/// Fails without touching user memory if any part of the range
+/// is not mapped user-accessible.
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), ()> {
+    check_user_range(src, dst.len(), false)?;
+    let _access = UserAccess::begin();
+    unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr::<u8>(), dst.as_mut_ptr(), dst.len());
+    }
+    Ok(())
+}
+
+/// Copies `src` to user address `dst`.
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), ()> {
+    check_user_range(dst, src.len(), true)?;
+    let _access = UserAccess::begin();
+    unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr(), dst.as_mut_ptr::<u8>(), src.len());
+    }
+    Ok(())
+}
+
+fn check_user_range(start: VirtAddr, len: usize, write: bool) -> Result<(), ()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = start.as_u64().checked_add(len as u64 - 1).ok_or(())?;
+    VirtAddr::try_new(end).map_err(|_| ())?;
+
+    let mut page = start.align_down(4096u64).as_u64();
+    while page <= end {
+        if !memory::user_accessible(VirtAddr::new(page), write) {
+            return Err(());
+        }
+        page += 4096;
+    }
+    Ok(())
+}
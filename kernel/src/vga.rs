@@ -1,6 +1,9 @@
+use crate::mmio::MmioRegion;
+use core::mem;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
+use x86_64::PhysAddr;
 
 #[macro_export]
 macro_rules! print {
@@ -21,6 +24,13 @@ pub fn _print(args: core::fmt::Arguments) {
     });
 }
 
+/// Prints raw bytes that may not be valid UTF-8, e.g. output of user programs.
+pub fn print_bytes(bytes: &[u8]) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        WRITER.lock().write_bytes(bytes);
+    });
+}
+
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
 
@@ -28,7 +38,7 @@ lazy_static! {
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: Buffer::default_buffer(),
+        buffer: Buffer::early_buffer(),
     });
 }
 
@@ -82,12 +92,30 @@ impl ColorCode {
     }
 }
 
+/// Physical address of the text mode buffer.
+const BUFFER_ADDRESS: u64 = 0xb8000;
+
 impl Buffer {
-    pub fn default_buffer() -> &'static mut Buffer {
-        unsafe { &mut *(0xb8000 as *mut Buffer) }
+    /// The buffer through the bootloader's identity mapping of the first
+    /// megabyte. Only used until `init` runs, since printing starts before
+    /// there is a memory manager.
+    fn early_buffer() -> &'static mut Buffer {
+        unsafe { &mut *(BUFFER_ADDRESS as *mut Buffer) }
     }
 }
 
+/// Moves the writer to a mapping of the buffer of its own.
+pub fn init() {
+    // the buffer is device memory, only ever written through the writer
+    let region =
+        unsafe { MmioRegion::map(PhysAddr::new(BUFFER_ADDRESS), mem::size_of::<Buffer>()) }
+            .expect("failed to map the VGA buffer");
+    let buffer = unsafe { region.leak::<Buffer>() };
+    x86_64::instructions::interrupts::without_interrupts(move || {
+        WRITER.lock().buffer = buffer;
+    });
+}
+
 impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
@@ -110,7 +138,11 @@ impl Writer {
     }
 
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
+        self.write_bytes(s.as_bytes())
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
             match byte {
                 // printable ASCII byte or newline
                 0x20..=0x7e | b'\n' => self.write_byte(byte),
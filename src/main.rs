@@ -4,8 +4,9 @@
 extern crate alloc;
 use bootloader::{entry_point, BootInfo};
 use kios_kernel::{
-    ktask::{executor::Executor, kernel_tasks::keyboard, KernelTask},
-    println,
+    cpu,
+    ktask::{executor::Executor, kernel_tasks::keyboard, KernelTask, Priority},
+    memory, println, process, programs,
 };
 
 entry_point!(main);
@@ -15,9 +16,29 @@ fn main(boot: &'static BootInfo) -> ! {
     kios_kernel::init(boot);
 
     println!(":: Kernel booted");
+    println!(":: CPU: {}", cpu::features());
+    println!(":: Memory map:\n{}", memory::info::MemoryMapReport);
+
+    println!(":: Running user program hello");
+    match process::spawn(programs::HELLO, &["hello"], &[], None) {
+        Ok(pid) => {
+            process::run(pid).expect("hello is runnable");
+            let status = process::try_wait(pid).expect("hello was spawned by us");
+            println!(":: hello (pid {}) exited: {:?}", pid, status);
+        }
+        Err(error) => println!(":: failed to load hello: {:?}", error),
+    }
+
+    println!(":: {}", memory::meminfo());
 
     println!(":: Spawning kernel tasks.");
     let mut executor = Executor::new();
-    executor.spawn(KernelTask::new(keyboard::print_keyevents()));
+    executor
+        .spawn(
+            KernelTask::new(keyboard::print_keyevents())
+                .with_name("keyboard")
+                .with_priority(Priority::Interactive),
+        )
+        .detach();
     executor.run();
 }